@@ -142,6 +142,30 @@ extern "C" void NOINSTR __cyg_profile_func_exit(void* func, void* caller) {}
 #include <fstream>
 #include <vector>
 #include <iostream>
+#include <sstream>
+#include <iomanip>
+#include <cstdlib>
+
+#include "funtrace_build_id.h"
+
+//the paths of the build IDs should match those in /proc/self/maps, so we resolve the symlinks in the names
+//the dynamic loader gives us
+static int NOINSTR build_id_callback(struct dl_phdr_info *info, size_t size, void *data)
+{
+    auto& out = *(std::ostream*)data;
+    char* path = realpath(info->dlpi_name[0] ? info->dlpi_name : "/proc/self/exe", nullptr);
+    if(!path) {
+        return 0; //the vDSO has no file
+    }
+    for(int i=0; i<info->dlpi_phnum; ++i ) {
+        const auto& phdr = info->dlpi_phdr[i];
+        if(phdr.p_type == PT_NOTE) {
+            print_build_id(out, (const char*)(info->dlpi_addr + phdr.p_vaddr), phdr.p_memsz, path);
+        }
+    }
+    free(path);
+    return 0;
+}
 
 NOINSTR CountsPagesL2::~CountsPagesL2()
 {
@@ -164,6 +188,14 @@ NOINSTR CountsPagesL2::~CountsPagesL2()
 
         maps_file.close();
         out.write(&maps_data[0], maps_data.size());
+
+        //funcount2sym checks the binaries it finds against these, like funtrace2viz does with the BUILDIDS chunk
+        std::stringstream buildids;
+        buildids << std::hex;
+        dl_iterate_phdr(build_id_callback, &buildids);
+        if(!buildids.str().empty()) {
+            out << "BUILDIDS\n" << buildids.str();
+        }
         out << "COUNTS\n";
     }
 
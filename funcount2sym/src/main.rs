@@ -1,6 +1,6 @@
 use procaddr2sym::ProcAddr2Sym;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use procaddr2sym::trace_file::parse_funcount;
+use std::fs;
 use std::env;
 
 macro_rules! fail {
@@ -16,50 +16,28 @@ fn main() {
         fail!("Usage: {} <funcount.txt> # counts with function names printed to stdout, pipe through c++filt if you want to demangle the symbols", args[0]);
     }
 
-    // Open the input file
-    let file = File::open(args[1].to_string()).expect("failed to open input file");
-    let mut reader = BufReader::new(file);
-
-    // Validate and parse the magic strings
-    let mut line = String::new();
-    reader.read_line(&mut line).expect("failed to read FUNCOUNT");
-    if line.trim() != "FUNCOUNT" { fail!("missing FUNCOUNT magic string - got `{}'", line); }
-    line.clear();
-
-    reader.read_line(&mut line).expect("failed to read PROCMAPS");
-    if line.trim() != "PROCMAPS" { fail!("missing PROCMAPS magic string - got `{}'", line); }
-    line.clear();
-
-    // Read and parse the memory maps
-    let mut proc_maps_data = String::new();
-    let mut found = false;
-    while reader.read_line(&mut line).expect("failure reading input file") > 0 {
-        if line.trim() == "COUNTS" {
-            found = true;
-            break;
-        }
-        proc_maps_data.push_str(&line);
-        line.clear();
-    }
-    if !found { fail!("COUNTS magic string not found"); }
-    line.clear();
+    let data = fs::read(&args[1]).expect("failed to read input file");
+    let text = String::from_utf8_lossy(&data);
+    let funcount = match parse_funcount(&text) {
+        Ok(funcount) => funcount,
+        Err(e) => fail!("{}", e),
+    };
 
     let input_source = Some(procaddr2sym::input_source(args[1].to_string()));
     let mut procaddr2sym = ProcAddr2Sym::new();
     procaddr2sym.input_source = input_source;
-    procaddr2sym.set_proc_maps(proc_maps_data.as_bytes());
-
-    while reader.read_line(&mut line).expect("failure reading input file") > 0 {
-        let parts: Vec<&str> = line.trim().split_whitespace().collect();
-        if parts.len() != 2 { fail!("Invalid address-count pair {}", line); }
-
-        let address = u64::from_str_radix(parts[0].trim_start_matches("0x"), 16).expect("bad address");
-        let count = parts[1].parse::<u64>().expect("bad count");
+    procaddr2sym.set_proc_maps(funcount.proc_maps);
+    if let Some(build_ids) = funcount.build_ids {
+        procaddr2sym.set_build_ids(build_ids);
+    }
+    // the symbols resolved by funsymbolize where the counts were taken
+    if let Some(symtable) = funcount.symtable {
+        procaddr2sym.set_symtable(symtable);
+    }
 
+    for (address, count) in funcount.counts {
         let syminfo = procaddr2sym.proc_addr2sym(address);
 
         println!("{} {:#x} {:#x} {} {} {}:{} {}", count, address, syminfo.static_addr, syminfo.size, syminfo.executable_file, syminfo.file, syminfo.line, syminfo.func);
-
-        line.clear();
     }
 }
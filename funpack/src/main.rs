@@ -0,0 +1,208 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use procaddr2sym::ProcAddr2Sym;
+use procaddr2sym::trace_file::{read_chunks, memory_map_groups, parse_funcount, MemoryMapGroup};
+use clap::{Parser, Subcommand};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde_json::{json, Value};
+
+const MANIFEST: &str = "manifest.json";
+
+#[derive(Parser)]
+#[clap(about="bundle funtrace.raw or funcount.txt with the binaries & source files needed to decode it into a .tar.gz archive, and unpack such archives elsewhere", version)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[clap(about="archive the input file together with the binaries in its memory maps, their separate debug info files and the source files of the traced functions")]
+    Pack {
+        #[clap(help="funtrace.raw or funcount.txt input file")]
+        input: String,
+        #[clap(help="the .tar.gz archive to create")]
+        archive: String,
+        #[clap(long="debug-dir", help="look for the separate debug info files of stripped binaries under this directory (by build ID or .gnu_debuglink), before those in $FUNTRACE_DEBUG_DIRS or /usr/lib/debug; can be passed multiple times")]
+        debug_dirs: Vec<String>,
+        #[clap(long="substitute-path", help="read the rules remapping the paths of binaries & source files from this JSON file rather than from $FUNTRACE_SUBSTITUTE_PATH or ./substitute-path.json")]
+        substitute_path: Option<String>,
+        #[clap(long="sysroot", help="look for the binaries & source files under this root directory, for processes running in a container or a chroot (like gdb's sysroot); can be passed multiple times to try several roots in order, before those in $FUNTRACE_SYSROOT")]
+        sysroots: Vec<String>,
+    },
+    #[clap(about="extract an archive made by `funpack pack` and write a substitute-path.json into the output directory, so that funtrace2viz or funcount2sym run there find the binaries & source files in the archive")]
+    Unpack {
+        #[clap(help="the .tar.gz archive made by `funpack pack`")]
+        archive: String,
+        #[clap(help="the directory to extract the archive into")]
+        dir: String,
+    },
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+//a file to archive: its kind ("executable", "debug" or "source"), the path it's referred to by in the trace or
+//the debug info, and where we read it from
+struct PackedFile {
+    kind: &'static str,
+    path: String,
+    local_file: String,
+}
+
+//the archive has the input file at the top, and the other files under files/<n>/<file name>, with a number per
+//original directory (so the archived paths don't depend on how deep the original ones were); the debug info files
+//found by build ID rather than .gnu_debuglink are kept under debug/.build-id to be found with --debug-dir
+struct ArchiveNames {
+    dirs: HashMap<String, usize>,
+}
+
+impl ArchiveNames {
+    fn dir(&mut self, path: &str) -> String {
+        let dir = Path::new(path).parent().map(|dir| dir.to_string_lossy().to_string()).unwrap_or_default();
+        let num = self.dirs.len();
+        format!("files/{}", self.dirs.entry(dir).or_insert(num))
+    }
+    fn file(&mut self, path: &str) -> String {
+        let name = Path::new(path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        format!("{}/{}", self.dir(path), name)
+    }
+}
+
+fn pack(input: &str, archive: &str, debug_dirs: &[String], sysroots: &[String], substitute_path: Option<&str>) -> io::Result<()> {
+    let data = fs::read(input)?;
+    let (text, chunks);
+    let groups = if data.starts_with(b"FUNCOUNT\n") {
+        text = String::from_utf8_lossy(&data);
+        let funcount = parse_funcount(&text)?;
+        let addresses = funcount.counts.iter().map(|&(address, _)| address).collect();
+        vec![MemoryMapGroup { proc_maps: funcount.proc_maps, build_ids: funcount.build_ids, addresses, ..Default::default() }]
+    } else {
+        chunks = read_chunks(&data)?;
+        memory_map_groups(&chunks)
+    };
+
+    let mut procaddr2sym = ProcAddr2Sym::new();
+    procaddr2sym.input_source = Some(procaddr2sym::input_source(input.to_string()));
+    procaddr2sym.debug_dirs.splice(0..0, debug_dirs.iter().cloned());
+    procaddr2sym.sysroots.splice(0..0, sysroots.iter().cloned());
+    if let Some(substitute_path) = substitute_path {
+        procaddr2sym.set_substitute_path(substitute_path).map_err(|e| invalid(&e))?;
+    }
+
+    let mut names = ArchiveNames { dirs: HashMap::new() };
+    let mut files: BTreeMap<String, PackedFile> = BTreeMap::new(); //by the name in the archive
+    for group in &groups {
+        procaddr2sym.set_proc_maps(group.proc_maps);
+        if let Some(build_ids) = group.build_ids {
+            procaddr2sym.set_build_ids(build_ids);
+        }
+        for mapped in procaddr2sym.mapped_files() {
+            files.insert(names.file(&mapped.path), PackedFile { kind: "executable", path: mapped.path.clone(), local_file: mapped.file });
+            let Some(debug_file) = mapped.debug_file else { continue };
+            //where funtrace2viz will look for it given the executable file's path in the archive
+            let name = match (mapped.debuglink, mapped.build_id) {
+                (Some(debuglink), _) => format!("{}/.debug/{}", names.dir(&mapped.path), debuglink),
+                (None, Some(build_id)) if build_id.len() > 2 => format!("debug/.build-id/{}/{}.debug", &build_id[..2], &build_id[2..]),
+                _ => continue,
+            };
+            files.insert(name, PackedFile { kind: "debug", path: debug_file.clone(), local_file: debug_file });
+        }
+        for &address in &group.addresses {
+            let sym = procaddr2sym.proc_addr2sym(address);
+            let mut source_files = vec![sym.file.clone()];
+            if let Some(lines) = procaddr2sym.function_lines(&sym) {
+                source_files.push(lines.decl_file);
+            }
+            for file in source_files {
+                if !file.starts_with('/') {
+                    continue;
+                }
+                let name = names.file(&file);
+                if files.contains_key(&name) {
+                    continue;
+                }
+                //the source file might be missing locally but available from debuginfod
+                let local_file = if fs::metadata(&file).is_ok() { Some(file.clone()) } else { procaddr2sym.fetch_source_file(&sym) };
+                //the debug info refers to the file by its path under the sysroot it was found in
+                let path = procaddr2sym.sysroots.iter().find_map(|sysroot| file.strip_prefix(sysroot.trim_end_matches('/')).filter(|path| path.starts_with('/')));
+                let path = path.unwrap_or(&file).to_string();
+                match local_file {
+                    Some(local_file) => { files.insert(name, PackedFile { kind: "source", path, local_file }); }
+                    None => println!("WARNING: couldn't open source file {} - leaving it out of the archive", file),
+                }
+            }
+        }
+    }
+
+    let input_name = Path::new(input).file_name().ok_or(invalid("no input file name"))?.to_string_lossy().to_string();
+    let manifest = json!({
+        "input": input_name,
+        "files": files.iter().map(|(name, file)| json!({"kind": file.kind, "path": file.path, "archived": name})).collect::<Vec<Value>>(),
+    });
+    let manifest = serde_json::to_vec_pretty(&manifest)?;
+
+    let mut tar = tar::Builder::new(GzEncoder::new(File::create(archive)?, Compression::default()));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0));
+    tar.append_data(&mut header, MANIFEST, manifest.as_slice())?;
+    tar.append_path_with_name(input, &input_name)?;
+    for (name, file) in &files {
+        if let Err(e) = tar.append_path_with_name(&file.local_file, name) {
+            println!("WARNING: failed to archive {} {} - {}", file.kind, file.local_file, e);
+        }
+    }
+    tar.into_inner()?.finish()?;
+    let count = |kind| files.values().filter(|file| file.kind == kind).count();
+    println!("packed {} with {} binaries, {} debug info files and {} source files into {}", input, count("executable"), count("debug"), count("source"), archive);
+    Ok(())
+}
+
+fn unpack(archive: &str, dir: &str) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    tar::Archive::new(GzDecoder::new(File::open(archive)?)).unpack(dir)?;
+    let dir = fs::canonicalize(dir)?;
+    let manifest: Value = serde_json::from_slice(&fs::read(dir.join(MANIFEST))?)?;
+    let input = manifest["input"].as_str().ok_or(invalid("no input file in the manifest"))?;
+
+    //the debug info files are found relatively to the executable files or with --debug-dir, so they needn't be remapped
+    let mut substitute_path = Vec::new();
+    for file in manifest["files"].as_array().ok_or(invalid("no files in the manifest"))? {
+        let (Some(kind), Some(path), Some(archived)) = (file["kind"].as_str(), file["path"].as_str(), file["archived"].as_str()) else {
+            return Err(invalid(&format!("bad file entry in the manifest: {}", file)));
+        };
+        let applies_to = match kind {
+            "executable" => "binaries",
+            "source" => "sources",
+            _ => continue,
+        };
+        //a prefix rule with the whole path only matches the file itself (and not /lib/libx.so.1 for /lib/libx.so)
+        substitute_path.push(json!({"prefix": path, "replace": dir.join(archived).to_string_lossy(), "applies_to": applies_to}));
+    }
+    fs::write(dir.join("substitute-path.json"), serde_json::to_vec_pretty(&substitute_path)?)?;
+
+    let debug_dir = dir.join("debug");
+    let is_funcount = fs::read(dir.join(input))?.starts_with(b"FUNCOUNT\n");
+    println!("unpacked {} into {}; to decode the {} using the archived files, run:", archive, dir.display(), input);
+    match (is_funcount, debug_dir.exists()) {
+        (false, false) => println!("  cd {} && funtrace2viz {} funtrace", dir.display(), input),
+        (false, true) => println!("  cd {} && funtrace2viz --debug-dir {} {} funtrace", dir.display(), debug_dir.display(), input),
+        (true, false) => println!("  cd {} && funcount2sym {}", dir.display(), input),
+        (true, true) => println!("  cd {} && FUNTRACE_DEBUG_DIRS={} funcount2sym {}", dir.display(), debug_dir.display(), input),
+    }
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    match Cli::parse().command {
+        Command::Pack { input, archive, debug_dirs, sysroots, substitute_path } => pack(&input, &archive, &debug_dirs, &sysroots, substitute_path.as_deref()),
+        Command::Unpack { archive, dir } => unpack(&archive, &dir),
+    }
+}
@@ -0,0 +1,88 @@
+use std::fs;
+use std::io;
+use procaddr2sym::ProcAddr2Sym;
+use procaddr2sym::trace_file::{read_chunks, write_chunk, memory_map_groups, parse_funcount};
+use clap::Parser;
+
+#[derive(Parser)]
+#[clap(about="resolve the addresses in funtrace.raw or funcount.txt into symbols where the trace was taken, and add them to the file in a SYMTABLE chunk, so that funtrace2viz and funcount2sym can decode it without the binaries", version)]
+struct Cli {
+    #[clap(help="funtrace.raw or funcount.txt input file")]
+    input: String,
+    #[clap(help="the output file; the input file is rewritten if not given")]
+    output: Option<String>,
+    #[clap(long, help="also save the source code of the traced functions, shown by vizviewer when clicking a function")]
+    sources: bool,
+    #[clap(long="debug-dir", help="look for the separate debug info files of stripped binaries under this directory (by build ID or .gnu_debuglink), before those in $FUNTRACE_DEBUG_DIRS or /usr/lib/debug; can be passed multiple times")]
+    debug_dirs: Vec<String>,
+    #[clap(long="substitute-path", help="read the rules remapping the paths of binaries & source files from this JSON file rather than from $FUNTRACE_SUBSTITUTE_PATH or ./substitute-path.json")]
+    substitute_path: Option<String>,
+    #[clap(long="sysroot", help="look for the binaries & source files under this root directory, for processes running in a container or a chroot (like gdb's sysroot); can be passed multiple times to try several roots in order, before those in $FUNTRACE_SYSROOT")]
+    sysroots: Vec<String>,
+}
+
+//a SYMTABLE chunk is added after every PROCMAPS chunk (and the BUILDIDS chunk following it) with the symbols
+//of the addresses in the TRACEBUF chunks up to the next PROCMAPS chunk. SYMTABLE chunks added by a previous run
+//are replaced
+fn symbolize_funtrace(procaddr2sym: &mut ProcAddr2Sym, data: &[u8], sources: bool) -> io::Result<Vec<u8>> {
+    let chunks = read_chunks(data)?;
+    let groups = memory_map_groups(&chunks);
+    let mut groups = groups.iter().peekable();
+    let mut out = Vec::with_capacity(data.len());
+    for (i, chunk) in chunks.iter().enumerate() {
+        if &chunk.magic == b"SYMTABLE" {
+            continue;
+        }
+        write_chunk(&mut out, &chunk.magic, &chunk.content);
+        let Some(group) = groups.next_if(|group| group.last_chunk == i) else { continue };
+        procaddr2sym.set_proc_maps(group.proc_maps);
+        if let Some(build_ids) = group.build_ids {
+            procaddr2sym.set_build_ids(build_ids);
+        }
+        let addresses: Vec<u64> = group.addresses.iter().copied().collect();
+        write_chunk(&mut out, b"SYMTABLE", &procaddr2sym.make_symtable(&addresses, sources));
+        println!("resolved {} unique addresses", addresses.len());
+    }
+    Ok(out)
+}
+
+//funcount.txt gets a "SYMTABLE" line after the counts, followed by the SYMTABLE on a single line
+fn symbolize_funcount(procaddr2sym: &mut ProcAddr2Sym, text: &str, sources: bool) -> io::Result<Vec<u8>> {
+    let funcount = parse_funcount(text)?;
+    procaddr2sym.set_proc_maps(funcount.proc_maps);
+    if let Some(build_ids) = funcount.build_ids {
+        procaddr2sym.set_build_ids(build_ids);
+    }
+    let addresses: Vec<u64> = funcount.counts.iter().map(|&(address, _)| address).collect();
+    let mut out = funcount.without_symtable.as_bytes().to_vec();
+    out.extend_from_slice(b"SYMTABLE\n");
+    out.extend_from_slice(&procaddr2sym.make_symtable(&addresses, sources));
+    out.push(b'\n');
+    println!("resolved {} addresses", addresses.len());
+    Ok(out)
+}
+
+fn main() -> io::Result<()> {
+    let args = Cli::parse();
+    let data = fs::read(&args.input)?;
+
+    let mut procaddr2sym = ProcAddr2Sym::new();
+    procaddr2sym.input_source = Some(procaddr2sym::input_source(args.input.clone()));
+    procaddr2sym.debug_dirs.splice(0..0, args.debug_dirs.iter().cloned());
+    procaddr2sym.sysroots.splice(0..0, args.sysroots.iter().cloned());
+    if let Some(ref substitute_path) = args.substitute_path {
+        procaddr2sym.set_substitute_path(substitute_path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+
+    let out = if data.starts_with(b"FUNCOUNT\n") {
+        symbolize_funcount(&mut procaddr2sym, &String::from_utf8_lossy(&data), args.sources)?
+    } else {
+        symbolize_funtrace(&mut procaddr2sym, &data, args.sources)?
+    };
+
+    //written under a temporary name first so that the input file isn't lost if we fail midway
+    let output = args.output.unwrap_or(args.input);
+    let tmp = format!("{}.{}.part", output, std::process::id());
+    fs::write(&tmp, out)?;
+    fs::rename(&tmp, &output)
+}
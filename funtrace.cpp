@@ -10,6 +10,7 @@
 #include <iostream>
 #include <fstream>
 #include <sstream>
+#include <iomanip>
 #include <set>
 #include <mutex>
 #include <vector>
@@ -499,11 +500,22 @@ static void NOINSTR write_chunk(std::ostream& file, const char* magic, const voi
     file.write((char*)data, bytes);
 }
 
-static void NOINSTR write_procmaps(std::ostream& file, std::stringstream& procmaps)
+static void NOINSTR write_chunk(std::ostream& file, const char* magic, std::stringstream& content)
 {
-    std::string s = std::move(procmaps).str();
-    write_chunk(file, "PROCMAPS", &s[0], s.size());
-    procmaps.str(std::move(s));
+    std::string s = std::move(content).str();
+    write_chunk(file, magic, &s[0], s.size());
+    content.str(std::move(s));
+}
+
+//the build IDs of the mapped objects follow the PROCMAPS chunk so that funtrace2viz can tell
+//if the binaries it finds are the ones the process ran (the chunk is optional, so we don't write
+//an empty one if none of the binaries has a build ID)
+static void NOINSTR write_procmaps(std::ostream& file, std::stringstream& procmaps, std::stringstream& buildids)
+{
+    write_chunk(file, "PROCMAPS", procmaps);
+    if(!buildids.str().empty()) {
+        write_chunk(file, "BUILDIDS", buildids);
+    }
 }
 
 struct event_buffer
@@ -551,11 +563,20 @@ static void NOINSTR write_ftrace(std::ostream& file, const std::vector<std::stri
     }
 }
 
+struct procmaps_and_buildids
+{
+    std::stringstream& procmaps;
+    std::stringstream& buildids;
+};
+
+#include "funtrace_build_id.h"
+
 //finding the executable segments using dl_iterate_phdr() is faster than reading /proc/self/maps
 //and produces less segments since we ignore the non-executable ones
 static int NOINSTR phdr_callback (struct dl_phdr_info *info, size_t size, void *data)
 {
-    std::stringstream& s = *(std::stringstream*)data;
+    auto& out = *(procmaps_and_buildids*)data;
+    const char* name = info->dlpi_name[0] ? info->dlpi_name : trace_state().exe_path;
     for(int i=0; i<info->dlpi_phnum; ++i ) {
         const auto& phdr = info->dlpi_phdr[i];
         //we only care about loadable executable segments (the likes of .text)
@@ -563,17 +584,21 @@ static int NOINSTR phdr_callback (struct dl_phdr_info *info, size_t size, void *
             //we print in "roughly" the format of /proc/self/maps, with arbitrary values for the fields we don't really care about
             uint64_t start_addr = info->dlpi_addr + phdr.p_vaddr;
             uint64_t end_addr = start_addr + phdr.p_memsz;
-            const char* name = info->dlpi_name[0] ? info->dlpi_name : trace_state().exe_path;
-            s << start_addr << '-' << end_addr << " r-xp " << phdr.p_offset << " 0:0 0 " << name << '\n';
+            out.procmaps << start_addr << '-' << end_addr << " r-xp " << phdr.p_offset << " 0:0 0 " << name << '\n';
+        }
+        if(phdr.p_type == PT_NOTE) {
+            print_build_id(out.buildids, (const char*)(info->dlpi_addr + phdr.p_vaddr), phdr.p_memsz, name);
         }
     }
     return 0;
 }
 
-static void NOINSTR get_procmaps(std::stringstream& procmaps)
+static void NOINSTR get_procmaps(std::stringstream& procmaps, std::stringstream& buildids)
 {
     procmaps << std::hex;
-    dl_iterate_phdr(phdr_callback, &procmaps);
+    buildids << std::hex;
+    procmaps_and_buildids out{procmaps, buildids};
+    dl_iterate_phdr(phdr_callback, &out);
 }
 
 extern "C" void NOINSTR funtrace_pause_and_write_current_snapshot()
@@ -583,9 +608,9 @@ extern "C" void NOINSTR funtrace_pause_and_write_current_snapshot()
     trace_state().pause_tracing();
 
     std::ostream& file = trace_state().file();
-    std::stringstream procmaps;
-    get_procmaps(procmaps);
-    write_procmaps(file, procmaps);
+    std::stringstream procmaps, buildids;
+    get_procmaps(procmaps, buildids);
+    write_procmaps(file, procmaps, buildids);
 
     //we don't allocate a snapshot - we save the memory for this by writing
     //straight from the trace buffers (at the expense of pausing tracing
@@ -615,6 +640,7 @@ struct funtrace_snapshot
     std::vector<event_buffer> thread_traces;
     std::vector<std::string> ftrace_events;
     std::stringstream procmaps;
+    std::stringstream buildids;
     NOINSTR funtrace_snapshot() {}
     NOINSTR ~funtrace_snapshot() {}
 };
@@ -636,7 +662,7 @@ funtrace_snapshot* NOINSTR funtrace_pause_and_get_snapshot()
     trace_state().resume_tracing();
 
     ftrace_events_snapshot(snapshot->ftrace_events);
-    get_procmaps(snapshot->procmaps);
+    get_procmaps(snapshot->procmaps, snapshot->buildids);
     return snapshot;
 }
 
@@ -654,7 +680,7 @@ extern "C" void NOINSTR funtrace_free_snapshot(funtrace_snapshot* snapshot)
 extern "C" void NOINSTR funtrace_write_snapshot(const char* filename, funtrace_snapshot* snapshot)
 {
     std::ofstream file(filename);
-    write_procmaps(file, snapshot->procmaps);
+    write_procmaps(file, snapshot->procmaps, snapshot->buildids);
     write_funtrace(file);
     write_tracebufs(file, snapshot->thread_traces);
     write_ftrace(file, snapshot->ftrace_events);
@@ -736,7 +762,7 @@ extern "C" struct funtrace_snapshot* NOINSTR funtrace_pause_and_get_snapshot_sta
     trace_state().resume_tracing();
 
     ftrace_events_snapshot(snapshot->ftrace_events, time);
-    get_procmaps(snapshot->procmaps);
+    get_procmaps(snapshot->procmaps, snapshot->buildids);
     return snapshot;
 }
 
@@ -0,0 +1,78 @@
+//the decoded calls of every thread in a sample, kept for the analyses that need to look
+//at the calls after all the threads were decoded (critical path, per-CPU tracks)
+use std::collections::HashMap;
+use procaddr2sym::SymInfo;
+
+#[derive(Clone, Copy)]
+pub struct RecordedCall {
+    pub start: u64,
+    pub end: u64,
+    pub sym: usize, //index into CallRecorder::syms
+}
+
+#[derive(Default)]
+struct ThreadCalls {
+    calls: Vec<RecordedCall>, //in the order of recording, and sorted by index() such that callers precede their callees
+    parents: Vec<Option<usize>>, //the innermost call enclosing each call (computed by index())
+}
+
+#[derive(Default)]
+pub struct CallRecorder {
+    pub syms: Vec<SymInfo>,
+    sym_index: HashMap<SymInfo, usize>,
+    calls: HashMap<u64, ThreadCalls>, //per TID
+}
+
+impl CallRecorder {
+    pub fn record_call(&mut self, tid: u64, sym: &SymInfo, start: u64, end: u64) -> RecordedCall {
+        let sym = match self.sym_index.get(sym) {
+            Some(&index) => index,
+            None => {
+                self.syms.push(sym.clone());
+                self.sym_index.insert(sym.clone(), self.syms.len()-1);
+                self.syms.len()-1
+            }
+        };
+        let call = RecordedCall { start, end, sym };
+        self.calls.entry(tid).or_default().calls.push(call);
+        call
+    }
+
+    //sorts the calls for calls_within(); called once all the calls were recorded
+    pub fn index(&mut self) {
+        for thread in self.calls.values_mut() {
+            thread.calls.sort_by_key(|call| (call.start, std::cmp::Reverse(call.end)));
+            //the calls of a thread nest, so the calls still running when a call starts are its caller,
+            //the caller's caller etc.
+            let mut stack: Vec<usize> = Vec::new();
+            thread.parents = thread.calls.iter().enumerate().map(|(i, call)| {
+                while stack.last().is_some_and(|&top| thread.calls[top].end <= call.start) {
+                    stack.pop();
+                }
+                let parent = stack.last().copied();
+                stack.push(i);
+                parent
+            }).collect();
+        }
+    }
+
+    //the calls of the thread overlapping [from, to), sorted such that callers precede their callees
+    //(only valid after index())
+    pub fn calls_within(&self, tid: u64, from: u64, to: u64) -> Vec<&RecordedCall> {
+        let Some(thread) = self.calls.get(&tid) else { return Vec::new() };
+        let first = thread.calls.partition_point(|call| call.start < from);
+        let last = thread.calls.partition_point(|call| call.start < to);
+        //the calls starting before the window and still running in it enclose the last call starting before it
+        let mut running: Vec<&RecordedCall> = Vec::new();
+        let mut enclosing = first.checked_sub(1);
+        while let Some(i) = enclosing {
+            if thread.calls[i].end > from {
+                running.push(&thread.calls[i]);
+            }
+            enclosing = thread.parents[i];
+        }
+        running.reverse();
+        running.extend(thread.calls[first..last.max(first)].iter().filter(|call| call.end > from));
+        running
+    }
+}
@@ -0,0 +1,275 @@
+//critical path analysis: walking backwards from the end of a slow call through the chain
+//of wakeups to find out which functions on which threads its latency really depended on
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use procaddr2sym::SymInfo;
+use regex::Regex;
+use num::FromPrimitive;
+use num::rational::Ratio;
+use crate::offcpu::{OffCpuStats, OffCpuState};
+use crate::calls::{CallRecorder, RecordedCall};
+use crate::rat2dec;
+
+//a wakeup chain longer than this is almost certainly a loop created by inaccurate ftrace data
+const MAX_WAKEUP_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SegmentKind {
+    OnCpu,
+    Runnable,
+    Blocked, //and we don't know who woke us (or the waker is the kernel)
+    Unfollowed(u64), //blocked until woken by this thread, which we didn't walk into at MAX_WAKEUP_DEPTH
+}
+
+struct Segment {
+    tid: u64,
+    start: u64,
+    end: u64,
+    kind: SegmentKind,
+}
+
+pub struct CriticalPath {
+    regex: Regex,
+    slowest: Option<(u64, RecordedCall)>,
+}
+
+impl CriticalPath {
+    pub fn new(regex: Regex) -> Self {
+        CriticalPath { regex, slowest: None }
+    }
+
+    pub fn check_call(&mut self, tid: u64, sym: &SymInfo, call: RecordedCall) {
+        let matches = self.regex.is_match(&sym.demangled_func) || self.regex.is_match(&sym.func);
+        if matches && self.slowest.is_none_or(|(_, slowest)| call.end - call.start > slowest.end - slowest.start) {
+            self.slowest = Some((tid, call));
+        }
+    }
+
+    //appends the segments of [from, to) on the thread to segments, in reverse chronological order;
+    //returns false if the wakeup chain was cut off at MAX_WAKEUP_DEPTH
+    fn walk(&self, sched: &OffCpuStats, tid: u64, from: u64, to: u64, depth: usize, segments: &mut Vec<Segment>) -> bool {
+        let mut complete = true;
+        let intervals = sched.thread_intervals(tid);
+        let mut i = intervals.partition_point(|interval| interval.start < to);
+        let mut t = to;
+        while t > from {
+            if i == 0 || intervals[i-1].end <= from {
+                segments.push(Segment { tid, start: from, end: t, kind: SegmentKind::OnCpu });
+                break;
+            }
+            i -= 1;
+            let interval = intervals[i];
+            if interval.end < t {
+                segments.push(Segment { tid, start: interval.end, end: t, kind: SegmentKind::OnCpu });
+                t = interval.end;
+            }
+            let start = interval.start.max(from);
+            match (interval.state, interval.waker) {
+                //we were waiting for the waker to get to the point where it woke us, so the waker's
+                //activity during the time we were blocked is what our latency depended on
+                (OffCpuState::Blocked, Some(waker)) if waker != 0 && waker != tid && depth < MAX_WAKEUP_DEPTH => {
+                    complete &= self.walk(sched, waker, start, t, depth+1, segments);
+                }
+                (OffCpuState::Blocked, Some(waker)) if waker != 0 && waker != tid => {
+                    segments.push(Segment { tid, start, end: t, kind: SegmentKind::Unfollowed(waker) });
+                    complete = false;
+                }
+                (OffCpuState::Blocked, _) => segments.push(Segment { tid, start, end: t, kind: SegmentKind::Blocked }),
+                (OffCpuState::Runnable, _) => segments.push(Segment { tid, start, end: t, kind: SegmentKind::Runnable }),
+            }
+            t = start;
+        }
+        complete
+    }
+
+    //the time spent in each function within [from, to) on the thread, excluding the time
+    //spent in its callees; the time not covered by any traced call is returned separately
+    fn exclusive_times(calls: &CallRecorder, tid: u64, from: u64, to: u64) -> (Vec<(usize, u64)>, u64) {
+        let mut exclusive: HashMap<usize, i64> = HashMap::new();
+        let mut stack: Vec<&RecordedCall> = Vec::new();
+        let mut covered = 0;
+        for call in calls.calls_within(tid, from, to) {
+            let overlap = (call.end.min(to) - call.start.max(from)) as i64;
+            while stack.last().is_some_and(|top| top.end <= call.start) {
+                stack.pop();
+            }
+            match stack.last() {
+                Some(parent) => *exclusive.entry(parent.sym).or_default() -= overlap,
+                None => covered += overlap as u64,
+            }
+            *exclusive.entry(call.sym).or_default() += overlap;
+            stack.push(call);
+        }
+        let mut times: Vec<(usize, u64)> = exclusive.into_iter().filter(|&(_, time)| time > 0).map(|(sym, time)| (sym, time as u64)).collect();
+        times.sort_by_key(|&(sym, time)| (std::cmp::Reverse(time), sym));
+        (times, (to - from).saturating_sub(covered))
+    }
+
+    pub fn write_report(&self, path: &str, sched: &OffCpuStats, calls: &CallRecorder, thread_names: &HashMap<u64, String>, cpu_freq: u64, time_base: u64) -> io::Result<bool> {
+        let Some((tid, slowest)) = self.slowest else { return Ok(false) };
+        let mut segments = Vec::new();
+        let complete = self.walk(sched, tid, slowest.start, slowest.end, 0, &mut segments);
+        segments.reverse();
+        if !complete {
+            println!("  WARNING: the wakeup chain is deeper than {} wakeups (possibly a loop created by inaccurate ftrace data) - the critical path wasn't followed past that depth", MAX_WAKEUP_DEPTH);
+        }
+
+        let rat = |n: u64| Ratio::from_u64(n).unwrap();
+        let us = |cycles: u64| rat2dec(&(rat(cycles) / (rat(cpu_freq) / rat(1000000))), 3);
+        let thread = |tid: u64| match (thread_names.get(&tid), sched.comms.get(&tid)) {
+            (Some(name), _) => format!("{} {}", tid, name),
+            (None, Some(comm)) => format!("{} {} (untraced)", tid, comm),
+            (None, None) => format!("{} (untraced)", tid),
+        };
+        let func = |sym: usize| format!("{} ({}:{})", calls.syms[sym].demangled_func, calls.syms[sym].file, calls.syms[sym].line);
+
+        let mut report = File::create(path)?;
+        writeln!(report, "# critical path of the slowest call of a function matching `{}` - {} on thread {}, {} us starting at cycle {}",
+                 self.regex, func(slowest.sym), thread(tid), us(slowest.end - slowest.start), slowest.start - time_base)?;
+        if !complete {
+            writeln!(report, "# WARNING: the wakeup chain is deeper than {} wakeups - not followed past that depth", MAX_WAKEUP_DEPTH)?;
+        }
+        writeln!(report, "# start cycle\ttime (us)\tthread\twhat")?;
+        //(thread, function) -> time; None is the time outside traced calls
+        let mut summary: HashMap<(u64, Option<usize>), u64> = HashMap::new();
+        let mut runnable = 0;
+        let mut blocked = 0;
+        let mut unfollowed = 0;
+        for segment in &segments {
+            let duration = segment.end - segment.start;
+            let what = match segment.kind {
+                SegmentKind::Runnable => { runnable += duration; "waiting for a CPU".to_string() }
+                SegmentKind::Blocked => { blocked += duration; "blocked (woken by the kernel or an unknown thread)".to_string() }
+                SegmentKind::Unfollowed(waker) => { unfollowed += duration; format!("blocked until woken by {} (the wakeup chain is too deep to follow further)", thread(waker)) }
+                SegmentKind::OnCpu => {
+                    let (times, untraced) = Self::exclusive_times(calls, segment.tid, segment.start, segment.end);
+                    let mut funcs: Vec<String> = times.iter().map(|&(sym, time)| format!("{} {} us", func(sym), us(time))).collect();
+                    for (sym, time) in times {
+                        *summary.entry((segment.tid, Some(sym))).or_default() += time;
+                    }
+                    if untraced > 0 {
+                        funcs.push(format!("untraced code {} us", us(untraced)));
+                        *summary.entry((segment.tid, None)).or_default() += untraced;
+                    }
+                    "running ".to_string() + &funcs.join(", ")
+                }
+            };
+            writeln!(report, "{}\t{}\t{}\t{}", segment.start - time_base, us(duration), thread(segment.tid), what)?;
+        }
+
+        writeln!(report, "\n# time on the critical path per thread & function (us)")?;
+        let mut summary: Vec<((u64, Option<usize>), u64)> = summary.into_iter().collect();
+        summary.sort_by_key(|&(key, time)| (std::cmp::Reverse(time), key));
+        for ((tid, sym), time) in summary {
+            writeln!(report, "{}\t{}\t{}", us(time), thread(tid), sym.map(func).unwrap_or("untraced code".to_string()))?;
+        }
+        if runnable > 0 {
+            writeln!(report, "{}\t\twaiting for a CPU", us(runnable))?;
+        }
+        if blocked > 0 {
+            writeln!(report, "{}\t\tblocked (woken by the kernel or an unknown thread)", us(blocked))?;
+        }
+        if unfollowed > 0 {
+            writeln!(report, "{}\t\tblocked past the depth the wakeup chain was followed to", us(unfollowed))?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ftrace::parse_ftrace_lines;
+    use crate::offcpu::tests::FTRACE;
+
+    fn segments(sched: &OffCpuStats, tid: u64, from: u64, to: u64) -> (Vec<(u64, u64, u64, SegmentKind)>, bool) {
+        let critical_path = CriticalPath::new(Regex::new(".").unwrap());
+        let mut segments = Vec::new();
+        let complete = critical_path.walk(sched, tid, from, to, 0, &mut segments);
+        segments.reverse();
+        (segments.iter().map(|s| (s.tid, s.start, s.end, s.kind)).collect(), complete)
+    }
+
+    #[test]
+    fn blocked_on_another_thread() {
+        let sched = OffCpuStats::new(&parse_ftrace_lines(FTRACE));
+        //while main (10) was blocked, the path goes through worker (20) which woke it up
+        assert_eq!(segments(&sched, 10, 40, 280), (vec![
+            (10, 40, 50, SegmentKind::OnCpu),
+            (10, 50, 100, SegmentKind::Runnable),
+            (10, 100, 150, SegmentKind::OnCpu),
+            (20, 150, 200, SegmentKind::OnCpu),
+            (10, 200, 260, SegmentKind::Runnable),
+            (10, 260, 280, SegmentKind::OnCpu),
+        ], true));
+    }
+
+    #[test]
+    fn wakeup_chain_too_deep() {
+        //thread k blocks at 100 and is woken up by thread k+1, which ran since shortly before then;
+        //the chain is longer than MAX_WAKEUP_DEPTH, so the walk stops at thread MAX_WAKEUP_DEPTH+1
+        let threads = MAX_WAKEUP_DEPTH as u64 + 2;
+        let mut lines: Vec<(u64, String)> = Vec::new();
+        for k in 1..=threads {
+            let woken = 1000 - 2*k;
+            lines.push((100, format!("t{k}-{k} [{k:03}] d.... 100: sched_switch: prev_comm=t{k} prev_pid={k} prev_prio=120 prev_state=S ==> next_comm=swapper next_pid=0 next_prio=120")));
+            if k < threads {
+                lines.push((woken, format!("t{}-{} [{:03}] d.... {woken}: sched_waking: comm=t{k} pid={k} prio=120 target_cpu={k:03}", k+1, k+1, k+1)));
+            }
+            lines.push((woken+1, format!("<idle>-0 [{k:03}] d.... {}: sched_switch: prev_comm=swapper prev_pid=0 prev_prio=120 prev_state=R ==> next_comm=t{k} next_pid={k} next_prio=120", woken+1)));
+        }
+        lines.sort();
+        let text: Vec<String> = lines.into_iter().map(|(_, line)| line).collect();
+        let sched = OffCpuStats::new(&parse_ftrace_lines(&text.join("\n")));
+
+        let (segments, complete) = segments(&sched, 1, 50, 999);
+        assert!(!complete);
+        let mut expected = vec![(1, 50, 100, SegmentKind::OnCpu)];
+        let last = MAX_WAKEUP_DEPTH as u64 + 1;
+        expected.push((last, 100, 1000 - 2*last, SegmentKind::Unfollowed(last+1)));
+        for k in (1..=last).rev() {
+            let woken = 1000 - 2*k;
+            expected.push((k, woken, woken+1, SegmentKind::Runnable));
+            if k > 1 {
+                expected.push((k, woken+1, woken+2, SegmentKind::OnCpu));
+            }
+        }
+        assert_eq!(segments, expected);
+    }
+
+    #[test]
+    fn report() {
+        let sched = OffCpuStats::new(&parse_ftrace_lines(FTRACE));
+        let sym = |name: &str| SymInfo { func: name.to_string(), demangled_func: name.to_string(), file: "a.cpp".to_string(), line: 1, executable_file: "a.out".to_string(), static_addr: 0, size: 0 };
+        let mut calls = CallRecorder::default();
+        let mut critical_path = CriticalPath::new(Regex::new("^wait").unwrap());
+        for (tid, name, start, end) in [(10, "wait", 40, 120), (10, "wait", 40, 280), (20, "produce", 120, 190), (20, "notify", 180, 190)] {
+            let call = calls.record_call(tid, &sym(name), start, end);
+            critical_path.check_call(tid, &sym(name), call);
+        }
+        calls.index();
+        let thread_names = HashMap::from([(10, "main".to_string())]);
+        let path = std::env::temp_dir().join(format!("critpath-test-{}.txt", std::process::id()));
+        assert!(critical_path.write_report(path.to_str().unwrap(), &sched, &calls, &thread_names, 1000000, 0).unwrap());
+        let report = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(report, "\
+# critical path of the slowest call of a function matching `^wait` - wait (a.cpp:1) on thread 10 main, 240.000 us starting at cycle 40
+# start cycle\ttime (us)\tthread\twhat
+40\t10.000\t10 main\trunning wait (a.cpp:1) 10.000 us
+50\t50.000\t10 main\twaiting for a CPU
+100\t50.000\t10 main\trunning wait (a.cpp:1) 50.000 us
+150\t50.000\t20 worker (untraced)\trunning produce (a.cpp:1) 30.000 us, notify (a.cpp:1) 10.000 us, untraced code 10.000 us
+200\t60.000\t10 main\twaiting for a CPU
+260\t20.000\t10 main\trunning wait (a.cpp:1) 20.000 us
+
+# time on the critical path per thread & function (us)
+80.000\t10 main\twait (a.cpp:1)
+30.000\t20 worker (untraced)\tproduce (a.cpp:1)
+10.000\t20 worker (untraced)\tuntraced code
+10.000\t20 worker (untraced)\tnotify (a.cpp:1)
+110.000\t\twaiting for a CPU
+");
+    }
+}
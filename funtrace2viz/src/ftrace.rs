@@ -0,0 +1,214 @@
+//parsing the textual ftrace data from FTRACETX chunks (what funtrace reads from trace_pipe) into typed
+//records. lines look like this:
+//  main-58704   [010] d.... 1473223221396767: sched_switch: prev_comm=main prev_pid=58704 prev_prio=120 prev_state=D ==> next_comm=swapper/10 next_pid=0 next_prio=120
+//(possibly with a "(tgid)" column after the task if the tracer's record-tgid option is set.)
+//events we don't have a typed record for are kept as a list of key=value fields (unless they don't
+//follow the key=value convention); in any case, the original line is kept for passing through to
+//systemTraceEvents. the typed records only hold the fields used by our analyses.
+
+#[derive(Debug, Clone)]
+pub struct SchedSwitch {
+    pub prev_comm: String,
+    pub prev_pid: u64,
+    pub prev_state: String, //R or R+ if preempted, S, D etc. if blocked
+    pub next_comm: String,
+    pub next_pid: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum FtraceRecord {
+    SchedSwitch(SchedSwitch),
+    //sched_waking, sched_wakeup & sched_wakeup_new (the event name tells which)
+    SchedWakeup { comm: String, pid: u64 },
+    SchedProcessFork { child_comm: String, child_pid: u64 },
+    SchedProcessExit { comm: String, pid: u64 },
+    Generic(Vec<(String, String)>), //key=value fields of other events
+    Text, //events not following the key=value convention
+}
+
+#[derive(Debug, Clone)]
+pub struct FtraceEvent {
+    pub timestamp: u64,
+    //the task logging the event (for sched_waking, the waker; 0 is the idle task, meaning interrupt context)
+    pub comm: String,
+    pub pid: u64,
+    pub cpu: Option<u32>,
+    pub name: String,
+    pub record: FtraceRecord,
+    line: String,
+    timestamp_pos: (usize, usize), //where the timestamp is in line
+}
+
+impl FtraceEvent {
+    //the original line with the timestamp replaced by the given text
+    pub fn line_with_timestamp(&self, timestamp: &str) -> String {
+        format!("{}{}{}", &self.line[..self.timestamp_pos.0], timestamp, &self.line[self.timestamp_pos.1..])
+    }
+
+    pub fn field(&self, key: &str) -> Option<&str> {
+        match &self.record {
+            FtraceRecord::Generic(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str()),
+            _ => None,
+        }
+    }
+}
+
+//splits "prev_comm=main prev_pid=58704 ... ==> next_comm=swapper/10 ..." into key=value pairs.
+//a value extends up to the next " key=", so values with spaces (such as comm) are fine.
+//returns None if the text doesn't start with a key=value pair
+fn parse_fields(text: &str) -> Option<Vec<(String, String)>> {
+    let is_key_char = |c: u8| c.is_ascii_alphanumeric() || c == b'_';
+    let bytes = text.as_bytes();
+    //(key start, value start) of each field
+    let mut starts: Vec<(usize, usize)> = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if (i == 0 || bytes[i-1] == b' ') && (bytes[i].is_ascii_alphabetic() || bytes[i] == b'_') {
+            let mut j = i;
+            while j < bytes.len() && is_key_char(bytes[j]) {
+                j += 1;
+            }
+            if j < bytes.len() && bytes[j] == b'=' {
+                starts.push((i, j+1));
+                i = j+1;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    if starts.first().is_none_or(|&(key, _)| key != 0) {
+        return None;
+    }
+    let mut fields = Vec::new();
+    for (n, &(key_start, value_start)) in starts.iter().enumerate() {
+        let end = if n+1 < starts.len() { starts[n+1].0 } else { text.len() };
+        let mut value = text[value_start..end].trim_end();
+        //sched_switch separates the "prev" and "next" fields with ==>
+        if let Some(v) = value.strip_suffix("==>") {
+            value = v.trim_end();
+        }
+        fields.push((text[key_start..value_start-1].to_string(), value.to_string()));
+    }
+    Some(fields)
+}
+
+//None if it's not an event we have a typed record for, or if it's missing some of the expected fields
+fn typed_record(name: &str, fields: &[(String, String)]) -> Option<FtraceRecord> {
+    let get = |key: &str| fields.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+    let num = |key: &str| get(key).and_then(|v| v.parse::<u64>().ok());
+    Some(match name {
+        "sched_switch" => FtraceRecord::SchedSwitch(SchedSwitch {
+            prev_comm: get("prev_comm")?, prev_pid: num("prev_pid")?, prev_state: get("prev_state")?,
+            next_comm: get("next_comm")?, next_pid: num("next_pid")?,
+        }),
+        "sched_waking" | "sched_wakeup" | "sched_wakeup_new" => FtraceRecord::SchedWakeup { comm: get("comm")?, pid: num("pid")? },
+        "sched_process_fork" => FtraceRecord::SchedProcessFork { child_comm: get("child_comm")?, child_pid: num("child_pid")? },
+        "sched_process_exit" => FtraceRecord::SchedProcessExit { comm: get("comm")?, pid: num("pid")? },
+        _ => return None,
+    })
+}
+
+//parses "  main-58704   [010] d.... " (or "  main-58704   (  58700) [010] d.... ") into comm, pid & CPU
+fn parse_task(prefix: &str) -> Option<(String, u64, Option<u32>)> {
+    let bracket = prefix.rfind(" [")?;
+    let cpu = prefix[bracket+2..].split(']').next().and_then(|cpu| cpu.trim().parse::<u32>().ok());
+    let mut task = prefix[..bracket].trim();
+    if task.ends_with(')') {
+        task = task[..task.rfind('(')?].trim_end();
+    }
+    let (comm, pid) = task.rsplit_once('-')?;
+    Some((comm.to_string(), pid.parse::<u64>().ok()?, cpu))
+}
+
+pub fn parse_ftrace_lines(input: &str) -> Vec<FtraceEvent> {
+    let mut results = Vec::new();
+
+    for line in input.lines() {
+        // Find the timestamp section
+        let Some(colon_pos) = line.find(": ") else { continue };
+        // Search backwards from colon to find the start of timestamp
+        let Some(space_before_ts) = line[..colon_pos].rfind(char::is_whitespace) else { continue };
+        // Parse the timestamp; lines without one aren't events (eg ftrace saying that it lost events)
+        let Ok(timestamp) = line[space_before_ts + 1..colon_pos].parse::<u64>() else { continue };
+
+        let (comm, pid, cpu) = parse_task(&line[..space_before_ts]).unwrap_or(("<...>".to_string(), 0, None));
+        let rest = &line[colon_pos+2..];
+        let (name, fields) = rest.split_once(": ").unwrap_or((rest.trim_end_matches(':'), ""));
+        let record = match parse_fields(fields) {
+            Some(fields) => typed_record(name, &fields).unwrap_or(FtraceRecord::Generic(fields)),
+            None => FtraceRecord::Text,
+        };
+
+        results.push(FtraceEvent {
+            timestamp, comm, pid, cpu, name: name.to_string(), record,
+            line: line.to_string(), timestamp_pos: (space_before_ts + 1, colon_pos),
+        });
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_line(line: &str) -> FtraceEvent {
+        let mut events = parse_ftrace_lines(line);
+        assert_eq!(events.len(), 1, "{}", line);
+        events.pop().unwrap()
+    }
+
+    #[test]
+    fn sched_switch() {
+        let event = parse_line("            main-58704   [010] d.... 1473223221396767: sched_switch: prev_comm=main prev_pid=58704 prev_prio=120 prev_state=D ==> next_comm=swapper/10 next_pid=0 next_prio=120");
+        assert_eq!((event.timestamp, event.comm.as_str(), event.pid, event.cpu, event.name.as_str()), (1473223221396767, "main", 58704, Some(10), "sched_switch"));
+        let FtraceRecord::SchedSwitch(switch) = event.record else { panic!("{:?}", event.record) };
+        assert_eq!((switch.prev_comm.as_str(), switch.prev_pid, switch.prev_state.as_str()), ("main", 58704, "D"));
+        assert_eq!((switch.next_comm.as_str(), switch.next_pid), ("swapper/10", 0));
+    }
+
+    #[test]
+    fn preempted_with_tgid_column() {
+        //with the record-tgid option, and a thread name with spaces & dashes
+        let event = parse_line(" my worker-3-58710 (  58704) [003] dN.2. 1473223221400000: sched_switch: prev_comm=my worker-3 prev_pid=58710 prev_prio=120 prev_state=R+ ==> next_comm=kworker/3:1 next_pid=120 next_prio=120");
+        assert_eq!((event.comm.as_str(), event.pid, event.cpu), ("my worker-3", 58710, Some(3)));
+        let FtraceRecord::SchedSwitch(switch) = event.record else { panic!("{:?}", event.record) };
+        assert_eq!((switch.prev_comm.as_str(), switch.prev_pid, switch.prev_state.as_str()), ("my worker-3", 58710, "R+"));
+        assert_eq!((switch.next_comm.as_str(), switch.next_pid), ("kworker/3:1", 120));
+    }
+
+    #[test]
+    fn wakeups() {
+        for (line, name) in [
+            ("            main-58704   [010] d..3. 1473223221400100: sched_wakeup_new: comm=main pid=58710 prio=120 target_cpu=003", "sched_wakeup_new"),
+            ("          <idle>-0       [003] dNh4. 1473223221400200: sched_waking: comm=main pid=58710 prio=120 target_cpu=003", "sched_waking"),
+            //older kernels have a success field
+            ("          <idle>-0       [003] dNh4. 1473223221400300: sched_wakeup: comm=main pid=58710 prio=120 success=1 target_cpu=003", "sched_wakeup"),
+        ] {
+            let event = parse_line(line);
+            assert_eq!(event.name, name);
+            let FtraceRecord::SchedWakeup { comm, pid } = event.record else { panic!("{:?}", event.record) };
+            assert_eq!((comm.as_str(), pid), ("main", 58710));
+        }
+    }
+
+    #[test]
+    fn other_events() {
+        let event = parse_line("            main-58704   [010] ..... 1473223221400400: task_rename: pid=58710 oldcomm=main newcomm=worker oom_score_adj=0");
+        assert!(matches!(event.record, FtraceRecord::Generic(_)));
+        assert_eq!((event.field("pid"), event.field("newcomm"), event.field("comm")), (Some("58710"), Some("worker"), None));
+
+        let event = parse_line("            main-58704   [010] ..... 1473223221400500: tracing_mark_write: hello world");
+        assert!(matches!(event.record, FtraceRecord::Text));
+        assert_eq!(event.line_with_timestamp("0.5"), "            main-58704   [010] ..... 0.5: tracing_mark_write: hello world");
+
+        //a typed event missing an expected field is kept as a generic one
+        let event = parse_line("            main-58704   [010] d.... 1473223221400600: sched_switch: prev_comm=main prev_pid=58704");
+        assert!(matches!(event.record, FtraceRecord::Generic(_)));
+    }
+
+    #[test]
+    fn lines_without_events() {
+        assert!(parse_ftrace_lines("CPU:10 [LOST 123 EVENTS]\n\n# tracer: nop\n").is_empty());
+    }
+}
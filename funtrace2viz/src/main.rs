@@ -1,24 +1,30 @@
-use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom};
+use std::fs::{self, File};
+use std::io::{self, BufReader};
 use std::io::prelude::*;
 use std::mem;
 use bytemuck::{Pod, Zeroable};
 use std::collections::{HashMap, HashSet};
-use procaddr2sym::{ProcAddr2Sym, SymInfo};
+use procaddr2sym::{ProcAddr2Sym, SymInfo, Instrumentation};
 use serde_json::Value;
 use clap::Parser;
 use std::cmp::{min, max};
 use num::{FromPrimitive, Zero};
 use num::rational::Ratio;
 use num::bigint::BigInt;
+use regex::Regex;
 
-const RETURN_BIT: i32 = 63;
-const RETURN_WITH_CALLER_ADDRESS_BIT: i32 = 62;
-const CATCH_MASK: u64 = (1<<RETURN_BIT)|(1<<RETURN_WITH_CALLER_ADDRESS_BIT);
-const CALL_RETURNING_UPON_THROW_BIT: i32 = 61;
-const ADDRESS_MASK: u64 = !(CATCH_MASK | (1<<CALL_RETURNING_UPON_THROW_BIT));
-const MAGIC_LEN: usize = 8;
-const LENGTH_LEN: usize = 8;
+mod ftrace;
+use ftrace::{FtraceEvent, parse_ftrace_lines};
+mod offcpu;
+use offcpu::OffCpuStats;
+mod critpath;
+use critpath::CriticalPath;
+mod calls;
+use calls::CallRecorder;
+use procaddr2sym::trace_file::{read_chunk, Chunk, RETURN_BIT, RETURN_WITH_CALLER_ADDRESS_BIT, CATCH_MASK, CALL_RETURNING_UPON_THROW_BIT, ADDRESS_MASK};
+
+//how many stacks emptied by catches to keep around for recovering from uninstrumented catchers
+const MAX_UNWOUND_STACKS: usize = 16;
 
 fn bit_set(n: u64, b: i32) -> bool { ((n>>b)&1) != 0 }
 
@@ -40,8 +46,8 @@ struct SourceCode {
 struct Cli {
     #[clap(help="funtrace.raw input file with one or more trace samples")]
     funtrace_raw: String,
-    #[clap(help="basename.json, basename.1.json, basename.2.json... are created, one JSON file per trace sample")]
-    out_basename: String,
+    #[clap(required_unless_present_any=&["grep", "dry"], help="basename.json, basename.1.json, basename.2.json... are created, one JSON file per trace sample")]
+    out_basename: Option<String>,
     #[clap(short, long, help="print the static addresses and executable/shared object files of decoded functions in addition to name, file & line")]
     executable_file_info: bool,
     #[clap(short, long, help="print the raw timestamps (the default is to subtract the timestamp of the earliest reported event at each sample, so that time starts at 0; in particular it helps to avoid rounding issues you might see with large timestamp values)")]
@@ -56,6 +62,32 @@ struct Cli {
     samples: Vec<u32>,
     #[clap(short, long, help="ignore threads with TIDs outside this list (including for the purpose of interpreting --max-event-age)")]
     threads: Vec<u64>,
+    #[clap(short, long, help="list every call of the functions with names matching this regex (sample, thread, start cycle, duration & caller chain) instead of decoding into JSON")]
+    grep: Option<String>,
+    #[clap(long, help="attribute the time threads were switched out according to ftrace scheduling events to the functions on their stacks, writing a per-function report of on-CPU, runnable & blocked time into basename.offcpu.txt, basename.1.offcpu.txt...")]
+    off_cpu: bool,
+    #[clap(long, help="draw flow arrows from the function running in a thread waking up another thread (according to ftrace's sched_waking events) to the function the woken thread resumed in")]
+    wakeup_flows: bool,
+    #[clap(long, help="walk backwards from the end of the slowest call of a function matching this regex through the wakeup chain, and write the functions & threads its latency depended on into basename.critpath.txt, basename.1.critpath.txt...")]
+    critical_path: Option<String>,
+    #[clap(long, help="add per-CPU tracks showing which traced thread & function ran on each CPU (according to ftrace's sched_switch events), and annotate calls during which the thread migrated between CPUs")]
+    cpu_tracks: bool,
+    #[clap(long, help="write a per-thread summary of decoding anomalies (call/return mismatches, orphan returns, frames closed by guesswork...) into basename.quality.json, basename.1.quality.json...")]
+    quality_report: bool,
+    #[clap(long, help="disassemble the traced functions to find tail calls, and show functions which tail-called others as returning when their tail callee returns (under instrumentation methods logging the return before the tail call)")]
+    tail_calls: bool,
+    #[clap(long, help="show the chain of inlined functions at the call site of returns logged with the caller's address (under XRay) and at the catch site of exceptions in the event args")]
+    inline_frames: bool,
+    #[clap(long="debug-dir", help="look for the separate debug info files of stripped binaries under this directory (by build ID or .gnu_debuglink), before those in $FUNTRACE_DEBUG_DIRS or /usr/lib/debug; can be passed multiple times")]
+    debug_dirs: Vec<String>,
+    #[clap(long="substitute-path", help="read the rules remapping the paths of binaries & source files from this JSON file rather than from $FUNTRACE_SUBSTITUTE_PATH or ./substitute-path.json")]
+    substitute_path: Option<String>,
+    #[clap(long="sysroot", help="look for the binaries & source files under this root directory, for processes running in a container or a chroot (like gdb's sysroot); can be passed multiple times to try several roots in order, before those in $FUNTRACE_SYSROOT")]
+    sysroots: Vec<String>,
+    #[clap(long="debuginfod", help="fetch the debug info & source files missing locally from this debuginfod server (http:// only), before those in $DEBUGINFOD_URLS; downloads are cached in $DEBUGINFOD_CACHE_PATH or ~/.cache/funtrace-debuginfod. can be passed multiple times")]
+    debuginfod_urls: Vec<String>,
+    #[clap(long, help="don't read or save the symbol index of each binary (its symbols & the locations of its traced functions, kept in $FUNTRACE_SYMBOL_INDEX_DIR or ~/.cache/funtrace-symbols to skip parsing the debug info in later runs)")]
+    no_symbol_index: bool,
 }
 
 struct TraceConverter {
@@ -75,6 +107,29 @@ struct TraceConverter {
     first_event_in_json: bool,
     first_event_in_thread: bool,
     num_events: i64,
+    grep: Option<Regex>,
+    sample_num: u32,
+    off_cpu_report: bool,
+    wakeup_flows: bool,
+    critical_path_regex: Option<Regex>,
+    cpu_tracks: bool,
+    quality_report: bool,
+    fix_tail_calls: bool,
+    //(executable file, static address) -> static addresses of the functions it tail-calls
+    tail_call_targets: HashMap<(String, u64), Vec<u64>>,
+    inline_frames: bool,
+    //return address -> the inlined functions at it (see inline_breadcrumb())
+    inline_breadcrumbs: HashMap<u64, Option<String>>,
+    //the call site of the next call event written, set when decoding a return logged with the caller's address
+    call_site: Option<String>,
+    //executable files whose instrumentation methods were already printed
+    reported_instrumentation: HashSet<String>,
+    //scheduling info from ftrace, used by --off-cpu, --wakeup-flows, --critical-path & --cpu-tracks
+    sched: Option<OffCpuStats>,
+    critical_path: Option<CriticalPath>,
+    recorded_calls: Option<CallRecorder>,
+    //call cycles of the current thread's calls recovered from stacks emptied by catches in uninstrumented functions
+    recovered_call_cycles: HashSet<u64>,
 }
 
 #[repr(C)]
@@ -86,50 +141,78 @@ struct ThreadID
     name: [u8; 16],
 }
 
-struct ThreadTrace {
-    thread_id: ThreadID,
-    trace: Vec<FunTraceEntry>,
+//counts of the places where decoding a thread's trace had to guess or found the trace broken
+//(also shown as instant events in the timeline, except for unwound_by_catches which are listed
+//in the catch events)
+#[derive(Default)]
+struct DecodeQuality {
+    mismatches: usize, //call/return mismatches
+    popped_frames: usize, //frames popped from the stack to recover from mismatches
+    orphan_returns: usize, //returns without a call, with the call time made up
+    ignored_thunks: usize, //virtual override thunk calls & returns
+    fake_returns: usize, //calls without a return, with the return time made up
+    catches: usize,
+    unwound_by_catches: usize, //frames closed at the time of the catch rather than by a return event
+    recovered_calls: usize, //calls recovered from stacks emptied by catches in uninstrumented functions
+    tail_calls: usize, //returns before a tail call which were moved to the tail-called function's return (--tail-calls)
 }
 
-struct FtraceEvent {
-    timestamp: u64,
-    line: String,
+impl DecodeQuality {
+    //window is the (effective) time range covered by the thread's trace
+    fn to_json(&self, thread_trace: &ThreadTrace, num_events: i64, window: (u64, u64)) -> Value {
+        let thread_id = &thread_trace.thread_id;
+        serde_json::json!({
+            "tid": thread_id.tid, "pid": thread_id.pid, "name": thread_name(thread_id), "events": num_events,
+            "window": [window.0, window.1], "buffer_wrapped": thread_trace.wrapped, "stale_entries_dropped": thread_trace.stale_entries,
+            "call_return_mismatches": self.mismatches, "popped_frames": self.popped_frames,
+            "orphan_returns": self.orphan_returns, "ignored_thunk_events": self.ignored_thunks,
+            "calls_without_return": self.fake_returns, "catches": self.catches,
+            "frames_unwound_by_catches": self.unwound_by_catches, "recovered_calls": self.recovered_calls,
+            "tail_calls_fixed": self.tail_calls,
+        })
+    }
 }
 
-fn parse_ftrace_lines(input: &String, transform_timestamp: impl Fn(u64) -> String) -> Vec<FtraceEvent> {
-    let mut results = Vec::new();
-
-    for line in input.lines() {
-        // Find the timestamp section
-        if let Some(colon_pos) = line.find(": ") {
-            // Search backwards from colon to find the start of timestamp
-            if let Some(space_before_ts) = line[..colon_pos].rfind(char::is_whitespace) {
-                let timestamp_str = &line[space_before_ts + 1..colon_pos];
-
-                // Parse the timestamp
-                if let Ok(timestamp) = timestamp_str.parse::<u64>() {
-                    // Split line into parts
-                    let before_ts = &line[..space_before_ts + 1];
-                    let after_ts = &line[colon_pos..];
-
-                    // Create modified line with transformed timestamp
-                    let modified_line = format!(
-                        "{}{}{}",
-                        before_ts,
-                        transform_timestamp(timestamp),
-                        after_ts
-                    );
-
-                    results.push(FtraceEvent {
-                        timestamp,
-                        line: modified_line,
-                    });
-                }
-            }
-        }
-    }
+//a call to write with write_function_call_event()
+#[derive(Clone, Copy)]
+struct DecodedCall<'a> {
+    sym: &'a SymInfo,
+    call_cycle: u64,
+    return_cycle: u64,
+    extra_ns: i32, //shifts the return timestamp if positive or the call timestamp if negative
+    callers: &'a [FunTraceEntry], //the stack entries below the call (only used by --grep)
+}
+
+//a catch, written by write_unwound_frames() along with the frames it unwound
+struct CatchEvent {
+    catcher_sym: SymInfo,
+    cycle: u64,
+    returns: Vec<(usize, String)>, //(stack depth, function) of the calls returned from since the last call
+    catch_site: Option<String>, //the --inline-frames breadcrumb of the catching code
+}
 
-    results
+struct ThreadTrace {
+    thread_id: ThreadID,
+    trace: Vec<FunTraceEntry>,
+    wrapped: bool, //the cyclic buffer wrapped around, overwriting the oldest entries
+    stale_entries: usize, //dropped by drop_stale_entries()
+}
+
+//drops the stale entries in a TRACEBUF (in the order of the cyclic buffer, before sorting), returning them
+//and the number of times the buffer wrapped around. the newest entry is the last one written before the
+//writeout, so the one following it in the cyclic order is the oldest one, and together they are the window
+//covered by the buffer. the writeout is racy, so we can see "new, old, new" - an entry that didn't make it into
+//the buffer in time left one from an earlier lap in its place, and such an entry is older than the oldest one.
+//the entries left are supposed to increase with a single drop where the buffer wraps around; more drops mean
+//that the buffer isn't what we think it is, and the caller warns about it
+fn drop_stale_entries(trace: &mut Vec<FunTraceEntry>) -> (Vec<FunTraceEntry>, usize) {
+    //(max_by_key returns the last of equal entries, which is the newest one if the buffer didn't wrap around)
+    let Some(newest) = (0..trace.len()).max_by_key(|&i| trace[i].cycle) else { return (Vec::new(), 0) };
+    let oldest = trace[(newest + 1) % trace.len()].cycle;
+    let (kept, stale): (Vec<FunTraceEntry>, Vec<FunTraceEntry>) = trace.iter().partition(|entry| entry.cycle >= oldest);
+    let wraparounds = kept.windows(2).filter(|pair| pair[1].cycle < pair[0].cycle).count();
+    *trace = kept;
+    (stale, wraparounds)
 }
 
 fn rat2dec(rat: &Ratio<BigInt>, decimal_places: u32) -> String {
@@ -174,15 +257,126 @@ fn rat2dec(rat: &Ratio<BigInt>, decimal_places: u32) -> String {
 
 impl TraceConverter {
     pub fn new(args: &Cli) -> Self {
-        TraceConverter { procaddr2sym: ProcAddr2Sym::new(), source_cache: HashMap::new(), sym_cache: HashMap::new(),
+        let mut procaddr2sym = ProcAddr2Sym::new();
+        procaddr2sym.debug_dirs.splice(0..0, args.debug_dirs.iter().cloned());
+        procaddr2sym.sysroots.splice(0..0, args.sysroots.iter().cloned());
+        if let Some(ref substitute_path) = args.substitute_path {
+            if let Err(e) = procaddr2sym.set_substitute_path(substitute_path) {
+                panic!("invalid --substitute-path file - {}", e);
+            }
+        }
+        if let Some(url) = args.debuginfod_urls.iter().find(|url| !url.starts_with("http://")) {
+            panic!("--debuginfod {} - only http:// servers are supported", url);
+        }
+        procaddr2sym.debuginfod.urls.splice(0..0, args.debuginfod_urls.iter().cloned());
+        if args.no_symbol_index {
+            procaddr2sym.symbol_index_dir = None;
+        }
+        procaddr2sym.report_symbol_index_hits = true;
+        TraceConverter { procaddr2sym, source_cache: HashMap::new(), sym_cache: HashMap::new(),
             max_event_age: args.max_event_age, raw_timestamps: args.raw_timestamps, time_base: 0,
             oldest_event_time: args.oldest_event_time, dry: args.dry,
             samples: args.samples.clone(), threads: args.threads.clone(), cpu_freq: 0, cmd_line: "".to_string(),
-            first_event_in_json: false, first_event_in_thread: false, num_events: 0
+            first_event_in_json: false, first_event_in_thread: false, num_events: 0,
+            grep: args.grep.as_ref().map(|re| Regex::new(re).expect("invalid --grep regex")), sample_num: 0,
+            off_cpu_report: args.off_cpu, wakeup_flows: args.wakeup_flows,
+            critical_path_regex: args.critical_path.as_ref().map(|re| Regex::new(re).expect("invalid --critical-path regex")),
+            cpu_tracks: args.cpu_tracks, quality_report: args.quality_report,
+            fix_tail_calls: args.tail_calls, tail_call_targets: HashMap::new(), reported_instrumentation: HashSet::new(),
+            inline_frames: args.inline_frames, inline_breadcrumbs: HashMap::new(), call_site: None,
+            sched: None, critical_path: None, recorded_calls: None, recovered_call_cycles: HashSet::new(),
         }
     }
 
-    fn oldest_event(&self, sample_entries: &Vec<ThreadTrace>, ftrace_events: &Vec<FtraceEvent>) -> u64 {
+    //in --grep mode we print the calls of matching functions and don't produce JSON files
+    fn writes_json(&self) -> bool {
+        !self.dry && self.grep.is_none()
+    }
+
+    fn print_grep_hit(&self, call_sym: &SymInfo, call_cycle: u64, return_cycle: u64, thread_id: &ThreadID, callers: &[FunTraceEntry]) {
+        let mut chain: Vec<&str> = callers.iter().map(|entry| self.sym_cache.get(&(entry.address & ADDRESS_MASK)).unwrap().demangled_func.as_str()).collect();
+        chain.push(&call_sym.demangled_func);
+        let name = thread_name(thread_id);
+        let rat = |n: u64| Ratio::from_u64(n).unwrap();
+        let dur_us = rat(return_cycle-call_cycle) / (rat(self.cpu_freq) / rat(1000000));
+        println!("sample {} tid {} {} - start {} duration {} cycles ({} us): {}", self.sample_num, thread_id.tid, name,
+                 call_cycle-self.time_base, return_cycle-call_cycle, rat2dec(&dur_us, 3), chain.join(" > "));
+    }
+
+    //a JSON timestamp (in microseconds since the time base)
+    fn cycle2us(&self, cycle: u64) -> String {
+        self.cycles2us(cycle-self.time_base)
+    }
+
+    //a duration rather than a timestamp
+    fn cycles2us(&self, cycles: u64) -> String {
+        let rat = |n: u64| Ratio::from_u64(n).unwrap();
+        rat2dec(&(rat(cycles) / (rat(self.cpu_freq) / rat(1000000))), 4)
+    }
+
+    //flow events are bound to the enclosing slices on their threads, so the arrow goes from
+    //the function that was running in the waker to the one the woken thread resumed in
+    fn write_wakeup_flows(&mut self, json: &mut File, sample_entries: &[ThreadTrace], oldest: u64) -> io::Result<()> {
+        let Some(sched) = &self.sched else { return Ok(()) };
+        let pids: HashMap<u64, u64> = sample_entries.iter().map(|t| (t.thread_id.tid, t.thread_id.pid)).collect();
+        let mut num_flows = 0;
+        for wakeup in &sched.wakeups {
+            let (Some(resumed), Some(waker_pid), Some(woken_pid)) = (wakeup.resumed, pids.get(&wakeup.waker), pids.get(&wakeup.woken)) else { continue };
+            if wakeup.timestamp < oldest {
+                continue;
+            }
+            num_flows += 1;
+            json.write_all(format!(r#"{}{{"ph":"s","id":{},"name":"wakeup","cat":"sched","pid":{},"tid":{},"ts":{}}}"#,
+                        if self.first_event_in_json { "" } else { "\n," }, num_flows, waker_pid, wakeup.waker, self.cycle2us(wakeup.timestamp)).as_bytes())?;
+            json.write_all(format!(r#"{}{{"ph":"f","bp":"e","id":{},"name":"wakeup","cat":"sched","pid":{},"tid":{},"ts":{}}}"#,
+                        "\n,", num_flows, woken_pid, wakeup.woken, self.cycle2us(resumed)).as_bytes())?;
+            self.first_event_in_json = false;
+        }
+        println!("  {} wakeup flows between traced threads", num_flows);
+        Ok(())
+    }
+
+    //each CPU is shown as a thread of a fake "CPUs" process; a traced thread's time on a CPU is a slice
+    //named after the thread, with the calls it made during that time nested under it
+    fn write_cpu_tracks(&mut self, json: &mut File, sample_entries: &[ThreadTrace], oldest: u64) -> io::Result<()> {
+        let (Some(sched), Some(recorded_calls)) = (&self.sched, &self.recorded_calls) else { return Ok(()) };
+        //the fake process's PID must not be that of a traced process, or its tracks would be mixed with the threads'
+        let cpus_pid = sample_entries.iter().map(|t| t.thread_id.pid).max().unwrap_or(0) + 1;
+        let names: HashMap<u64, String> = sample_entries.iter().map(|t| (t.thread_id.tid, thread_name(&t.thread_id))).collect();
+        let mut first_event = self.first_event_in_json;
+        let mut cpus: HashSet<u32> = HashSet::new();
+        let mut num_runs = 0;
+        for run in &sched.cpu_runs {
+            let Some(name) = names.get(&run.tid) else { continue };
+            if run.end < oldest {
+                continue;
+            }
+            let start = run.start.max(oldest);
+            if cpus.insert(run.cpu) {
+                json.write_all(format!(r#"{}{{"name":"thread_name","ph":"M","pid":{},"tid":{},"args":{{"name":"CPU {}"}}}}"#,
+                            if first_event { "" } else { "\n," }, cpus_pid, run.cpu, run.cpu).as_bytes())?;
+                first_event = false;
+            }
+            num_runs += 1;
+            json.write_all(format!(r#"{}{{"tid":{},"ts":{},"dur":{},"name":{},"ph":"X","pid":{},"cat":"sched"}}"#, "\n,",
+                        run.cpu, self.cycle2us(start), self.cycles2us(run.end - start),
+                        Value::String(format!("{} {}", run.tid, name)), cpus_pid).as_bytes())?;
+            for call in recorded_calls.calls_within(run.tid, start, run.end) {
+                let (call_start, call_end) = (call.start.max(start), call.end.min(run.end));
+                json.write_all(format!(r#"{}{{"tid":{},"ts":{},"dur":{},"name":{},"ph":"X","pid":{}}}"#, "\n,",
+                            run.cpu, self.cycle2us(call_start), self.cycles2us(call_end - call_start),
+                            json_name(&recorded_calls.syms[call.sym]), cpus_pid).as_bytes())?;
+            }
+        }
+        if !cpus.is_empty() {
+            json.write_all(format!(r#"{}{{"name":"process_name","ph":"M","pid":{},"args":{{"name":"CPUs"}}}}"#, "\n,", cpus_pid).as_bytes())?;
+        }
+        println!("  {} runs of traced threads on {} CPUs", num_runs, cpus.len());
+        self.first_event_in_json = first_event;
+        Ok(())
+    }
+
+    fn oldest_event(&self, sample_entries: &Vec<ThreadTrace>, ftrace_events: &[FtraceEvent]) -> u64 {
         let mut youngest = 0;
         let mut oldest = u64::MAX;
         for entries in sample_entries {
@@ -206,23 +400,107 @@ impl TraceConverter {
         }
     }
 
-    //extra_ns shifts the return timestamp if positive or the call timestamp if negative
-    fn write_function_call_event(&mut self, json: &mut File, call_sym: &SymInfo, call_cycle: u64, return_cycle: u64, extra_ns: i32, thread_id: &ThreadID, funcset: &mut HashSet<SymInfo>) -> io::Result<()> {
+    //closes frames[first..] at the catch cycle, innermost first, and writes the catch event listing them. these all
+    //end at the same cycle contrary to the JSON spec's perfect nesting requirement; unlike XRay we try to make them
+    //stand apart by 1 ns (the timeline's precision), also makes testing more straightforward
+    fn write_unwound_frames(&mut self, json: &mut File, frames: &[FunTraceEntry], first: usize, catch: &CatchEvent, thread_id: &ThreadID, funcset: &mut HashSet<SymInfo>) -> io::Result<()> {
+        for (unwound, i) in (first..frames.len()).rev().enumerate() {
+            let call_sym = self.sym_cache.get(&(frames[i].address & ADDRESS_MASK)).unwrap().clone();
+            self.write_function_call_event(json, &DecodedCall { sym: &call_sym, call_cycle: frames[i].cycle, return_cycle: catch.cycle, extra_ns: unwound as i32 + 1, callers: &frames[..i] }, thread_id, funcset)?;
+        }
+        self.write_catch_event(json, catch, &frames[first..], first, thread_id)
+    }
+
+    //whether the function caller tail-calls the function at callee_addr (according to its disassembly)
+    fn tail_calls(&mut self, caller: &SymInfo, callee_addr: u64) -> bool {
+        let callee = match self.sym_cache.get(&callee_addr) {
+            Some(sym) => sym.clone(),
+            None => self.procaddr2sym.proc_addr2sym(callee_addr),
+        };
+        if caller.executable_file != callee.executable_file || callee.size == 0 {
+            return false;
+        }
+        let key = (caller.executable_file.clone(), caller.static_addr);
+        if !self.tail_call_targets.contains_key(&key) {
+            //no need to disassemble functions which can't have tail call artifacts given their instrumentation method
+            //(if we couldn't detect the method, we assume it might have them)
+            let methods = self.procaddr2sym.instrumentation(&caller.executable_file);
+            let targets = if !methods.is_empty() && !methods.iter().any(|method| method.has_tail_call_artifacts()) { Vec::new() }
+                else { self.procaddr2sym.tail_call_targets(caller) };
+            self.tail_call_targets.insert(key.clone(), targets);
+        }
+        self.tail_call_targets[&key].contains(&callee.static_addr)
+    }
+
+    //an instant event on the catching thread. the unwound frames are those the catch closed at its timestamp since their
+    //instrumentation doesn't log returns upon throw, and depth is the stack depth below them; the calls returned from
+    //deeper than that since the last call might have been closed by return events logged during the unwinding, or might
+    //have returned normally before the throw, so they're listed as returned before the catch
+    fn write_catch_event(&mut self, json: &mut File, catch: &CatchEvent, unwound: &[FunTraceEntry], depth: usize, thread_id: &ThreadID) -> io::Result<()> {
+        if !self.writes_json() {
+            return Ok(());
+        }
+        let returned = catch.returns.iter().filter(|(returned_depth, _)| *returned_depth > depth).map(|(_, name)| format!(r#"{{"function":{},"closed_by":"returned_before_catch"}}"#, name));
+        let guessed = unwound.iter().rev().map(|frame| format!(r#"{{"function":{},"closed_by":"catch"}}"#, json_name(self.sym_cache.get(&(frame.address & ADDRESS_MASK)).unwrap())));
+        let unwound: Vec<String> = returned.chain(guessed).collect();
+        let site = catch.catch_site.as_ref().map(|site| format!(r#","catch_site":{}"#, Value::String(site.clone()))).unwrap_or_default();
+        self.write_instant_event(json, "catch", "exception", catch.cycle, thread_id, &format!(r#""catcher":{},"unwound":[{}]{}"#, json_name(&catch.catcher_sym), unwound.join(","), site))
+    }
+
+    //with --inline-frames, the chain of inlined functions at a return address - "f (a.cpp:10) > g (b.h:20)",
+    //the outermost first, each with the line it was at when the call was made
+    fn inline_breadcrumb(&mut self, return_address: u64) -> Option<String> {
+        if !self.inline_frames {
+            return None;
+        }
+        if !self.inline_breadcrumbs.contains_key(&return_address) {
+            let frames = self.procaddr2sym.inline_frames(return_address - 1);
+            let breadcrumb = frames.iter().rev().map(|frame| format!("{} ({}:{})", frame.demangled_func, frame.file, frame.line)).collect::<Vec<String>>().join(" > ");
+            self.inline_breadcrumbs.insert(return_address, if frames.is_empty() { None } else { Some(breadcrumb) });
+        }
+        self.inline_breadcrumbs[&return_address].clone()
+    }
+
+    //anomalies are places where the decoding had to guess or where the trace looks broken
+    fn write_anomaly_event(&mut self, json: &mut File, name: &str, cycle: u64, thread_id: &ThreadID, args: &str) -> io::Result<()> {
+        self.write_instant_event(json, name, "anomaly", cycle, thread_id, args)
+    }
+
+    //args are the fields of the args object, without the braces
+    fn write_instant_event(&mut self, json: &mut File, name: &str, cat: &str, cycle: u64, thread_id: &ThreadID, args: &str) -> io::Result<()> {
+        if !self.writes_json() {
+            return Ok(());
+        }
+        json.write_all(format!(r#"{}{{"tid":{},"ts":{},"name":"{}","ph":"i","s":"t","pid":{},"cat":"{}","args":{{{}}}}}"#,
+                    if self.first_event_in_json { "" } else { "\n," }, thread_id.tid, self.cycle2us(cycle), name, thread_id.pid,
+                    cat, args).as_bytes())?;
+        self.first_event_in_json = false;
+        Ok(())
+    }
+
+    fn write_function_call_event(&mut self, json: &mut File, call: &DecodedCall, thread_id: &ThreadID, funcset: &mut HashSet<SymInfo>) -> io::Result<()> {
+        let DecodedCall { sym: call_sym, call_cycle, return_cycle, extra_ns, callers } = *call;
         self.num_events += 1;
+        let call_site = self.call_site.take();
+        if let Some(ref grep) = self.grep {
+            if call_cycle != 0 && return_cycle != 0 && (grep.is_match(&call_sym.demangled_func) || grep.is_match(&call_sym.func)) {
+                self.print_grep_hit(call_sym, call_cycle, return_cycle, thread_id, callers);
+            }
+            return Ok(());
+        }
         if self.dry {
             return Ok(());
         }
         if self.first_event_in_thread {
-            let name: Vec<_> = thread_id.name.iter().filter(|&&x| x != 0 as u8).copied().collect();
-            json.write(format!(r#"{}{{"ph":"M","pid":{},"tid":{},"name":"thread_name","args":{{"name":{}}}}}"#,
+            json.write_all(format!(r#"{}{{"ph":"M","pid":{},"tid":{},"name":"thread_name","args":{{"name":{}}}}}"#,
                         if self.first_event_in_json { "" } else { "\n," },
-                        thread_id.pid,thread_id.tid,Value::String(String::from_utf8(name).unwrap()).to_string()).as_bytes())?;
+                        thread_id.pid,thread_id.tid,Value::String(thread_name(thread_id))).as_bytes())?;
             self.first_event_in_thread = false;
             self.first_event_in_json = false;
 
             if thread_id.pid == thread_id.tid {
-                json.write(format!(r#"{}{{"ph":"M","pid":{},"tid":{},"name":"process_name","args":{{"name":{}}}}}"#, "\n,",
-                        thread_id.pid,thread_id.tid,Value::String(self.cmd_line.clone()).to_string()).as_bytes())?;
+                json.write_all(format!(r#"{}{{"ph":"M","pid":{},"tid":{},"name":"process_name","args":{{"name":{}}}}}"#, "\n,",
+                        thread_id.pid,thread_id.tid,Value::String(self.cmd_line.clone())).as_bytes())?;
             }
         }
         //using f64 would lose precision for machines with an uptime > month since f64 stores
@@ -247,11 +525,44 @@ impl TraceConverter {
             //or a return timestamp. however, the last orphan B event seems to be missing from Perfetto's rendering
             //and all of the orphan E events seem to be missing; B and E are apparently mostly designed to come in pairs
             //(despite the beautiful gradient that orphan B events are rendered with)
-            json.write(format!(r#"{}{{"tid":{},"ts":{},"dur":{},"name":{},"ph":"X","pid":{}}}"#, "\n,",
+            let mut args: Vec<String> = Vec::new();
+            if let (true, Some(sched)) = (self.off_cpu_report, &mut self.sched) {
+                let (runnable, blocked) = sched.add_call(thread_id.tid, call_sym, call_cycle, return_cycle);
+                if runnable + blocked > 0 {
+                    args.push(format!(r#""runnable_us":{},"blocked_us":{}"#,
+                        rat2dec(&(rat(runnable)/cycles_per_us.clone()), 3), rat2dec(&(rat(blocked)/cycles_per_us.clone()), 3)));
+                }
+            }
+            if unsafe { PRINT_BIN_INFO } {
+                if let Some(id) = self.procaddr2sym.xray_function_id(call_sym) {
+                    args.push(format!(r#""xray_function_id":{}"#, id));
+                }
+            }
+            if let Some(site) = call_site {
+                args.push(format!(r#""call_site":{}"#, Value::String(site)));
+            }
+            if self.recovered_call_cycles.contains(&call_cycle) {
+                args.push(r#""recovered_after_uninstrumented_catch":true"#.to_string());
+            }
+            if let (true, Some(sched)) = (self.cpu_tracks, &self.sched) {
+                let cpus = sched.thread_cpus(thread_id.tid, call_cycle, return_cycle);
+                if cpus.len() > 1 {
+                    let migrations: Vec<String> = cpus.iter().map(|cpu| cpu.to_string()).collect();
+                    args.push(format!(r#""cpu_migrations":"{}""#, migrations.join("->")));
+                }
+            }
+            let args = if args.is_empty() { String::new() } else { format!(r#","args":{{{}}}"#, args.join(",")) };
+            json.write_all(format!(r#"{}{{"tid":{},"ts":{},"dur":{},"name":{},"ph":"X","pid":{}{}}}"#, "\n,",
                         thread_id.tid,
                         rat2dec(&(rat(call_cycle-self.time_base)/cycles_per_us.clone() - extra_call.clone()), digits),
                         rat2dec(&(rat(return_cycle-call_cycle)/cycles_per_us + extra_call + extra_ret), digits),
-                        json_name(call_sym), thread_id.pid).as_bytes())?; 
+                        json_name(call_sym), thread_id.pid, args).as_bytes())?; 
+            if let Some(ref mut recorded_calls) = self.recorded_calls {
+                let call = recorded_calls.record_call(thread_id.tid, call_sym, call_cycle, return_cycle);
+                if let Some(ref mut critical_path) = self.critical_path {
+                    critical_path.check_call(thread_id.tid, call_sym, call);
+                }
+            }
         }    
 
         funcset.insert(call_sym.clone());
@@ -259,7 +570,14 @@ impl TraceConverter {
         //cache the source code if it's the first time we see this file
         if !self.source_cache.contains_key(&call_sym.file) {
             let mut source_code: Vec<u8> = Vec::new();
-            if let Ok(mut source_file) = File::open(&call_sym.file) {
+            //the code saved in the SYMTABLE is what the traced program was built from, unlike the local file which may have changed since
+            let symtable_source = self.procaddr2sym.symtable_source(&call_sym.file);
+            //the source file might be missing locally but available from debuginfod
+            let source_path = if symtable_source.is_some() { None } else if fs::metadata(&call_sym.file).is_ok() { Some(call_sym.file.clone()) } else { self.procaddr2sym.fetch_source_file(call_sym) };
+            if let Some(source) = symtable_source {
+                source_code = source.into_bytes();
+            }
+            else if let Some(mut source_file) = source_path.and_then(|path| File::open(path).ok()) {
                 source_file.read_to_end(&mut source_code)?;
             }
             else if call_sym.file != "??" {
@@ -273,15 +591,15 @@ impl TraceConverter {
         Ok(())
     }
 
-    fn write_sample_to_json(&mut self, fname: &String, sample_entries: &Vec<ThreadTrace>, ftrace_text: &String) -> io::Result<()> {
-        let mut json = if self.dry { File::open("/dev/null")? } else { File::create(fname)? };
-        if !self.dry {
-            json.write(br#"{
+    fn write_sample_to_json(&mut self, fname: &String, sample_entries: &Vec<ThreadTrace>, ftrace_text: &str) -> io::Result<()> {
+        let mut json = if !self.writes_json() { File::open("/dev/null")? } else { File::create(fname)? };
+        if self.writes_json() {
+            json.write_all(br#"{
 "traceEvents": [
 "#)?;
             println!("decoding a trace sample logged by `{}` into {} ...", self.cmd_line, fname);
         }
-        else {
+        else if self.dry {
             println!("inspecting sample {} logged by `{}` (without creating the file...)", fname, self.cmd_line);
         }
     
@@ -292,24 +610,26 @@ impl TraceConverter {
         let mut funcset: HashSet<SymInfo> = HashSet::new();
         self.first_event_in_json = true;
         let mut ignore_addrs: HashSet<u64> = HashSet::new();
+        //catching function -> number of exceptions it caught
+        let mut catches: HashMap<String, u64> = HashMap::new();
+        let mut threads_quality: Vec<Value> = Vec::new();
+        let mut instrumentation: Vec<Value> = Vec::new();
     
-        let rat = |n: u64| Ratio::from_u64(n).unwrap();
-        //ftrace timestamps are supposed to be in seconds; CPU frequency is in TSC cycles per second;
-        //so dividing by frequency will convert TSC to seconds. Perfetto timeline accuracy is ns
-        //hence 10 digits after '.' (9 plus another to make sure different cycles don't become the same ns)
-        let cycles_per_second = rat(self.cpu_freq);
-        let fixts = |ts: u64| format!("{}", rat2dec(&(rat(ts)/cycles_per_second.clone()), 10));
-        let mut ftrace_events = parse_ftrace_lines(ftrace_text, fixts);
+        //(parsed by --grep as well, since the ftrace events can be older than the function calls, and then they
+        //set the time base the start cycles of the calls are printed relatively to)
+        let mut ftrace_events = parse_ftrace_lines(ftrace_text);
+
+        //computed before old events are dropped below, since a thread switched out long ago
+        //might still be off-CPU during the time range we're interested in
+        let needs_sched = self.off_cpu_report || self.wakeup_flows || self.critical_path_regex.is_some() || self.cpu_tracks;
+        self.sched = if needs_sched && self.writes_json() { Some(OffCpuStats::new(&ftrace_events)) } else { None };
+        self.critical_path = if self.writes_json() { self.critical_path_regex.clone().map(CriticalPath::new) } else { None };
+        let needs_calls = self.critical_path.is_some() || self.cpu_tracks;
+        self.recorded_calls = if needs_calls && self.writes_json() { Some(CallRecorder::default()) } else { None };
 
         let oldest = self.oldest_event(sample_entries, &ftrace_events);
         self.time_base = if self.raw_timestamps { 0 } else { oldest };
 
-        if self.time_base > 0 {
-            //TODO: a bit wasteful to reparse this just to subtract the time base 
-            let fixts = |ts: u64| format!("{}", rat2dec(&(rat(ts-self.time_base)/cycles_per_second.clone()), 10));
-            ftrace_events = parse_ftrace_lines(ftrace_text, fixts);
-        }
-    
         ftrace_events.retain(|event| event.timestamp >= oldest);
 
         for thread_trace in sample_entries {
@@ -326,8 +646,21 @@ impl TraceConverter {
             self.first_event_in_thread = true;
 
             let mut expecting_to_return_into_sym = self.procaddr2sym.unknown_symbol();
+            //(stack depth, function) of the calls returned from since the last call whose call entries have
+            //CALL_RETURNING_UPON_THROW_BIT set; if a catch follows, those deeper than the catcher might have been
+            //returned from by the stack unwinding. this can't tell a function returning normally right before
+            //the throw from one being unwound, and misses the frames unwound before a destructor was called;
+            //it's only used to annotate catch events
+            let mut returns_since_call: Vec<(usize, SymInfo)> = Vec::new();
+            //stacks fully popped by catches, kept to recover from uninstrumented catchers; their catch events are
+            //written once we know which of their frames were unwound and which were recovered
+            let mut unwound_stacks: Vec<(Vec<FunTraceEntry>, CatchEvent)> = Vec::new();
+            let mut quality = DecodeQuality::default();
+            //call cycles of the frames kept on the stack after returning into a tail call (see --tail-calls)
+            let mut tail_callers: HashSet<u64> = HashSet::new();
+            self.recovered_call_cycles.clear();
     
-            for entry in entries {
+            for (entry_index, entry) in entries.iter().enumerate() {
                 if oldest > entry.cycle {
                     continue; //ignore old events
                 }
@@ -348,6 +681,10 @@ impl TraceConverter {
                     self.sym_cache.insert(addr, sym);
                 }
                 if ignore_addrs.contains(&addr) {
+                    quality.ignored_thunks += 1;
+                    let thunk = json_name(self.sym_cache.get(&addr).unwrap());
+                    self.write_anomaly_event(&mut json, "ignored virtual override thunk", entry.cycle, &thread_trace.thread_id,
+                        &format!(r#""function":{},"event":"{}""#, thunk, if catch { "catch" } else if ret { "return" } else { "call" }))?;
                     continue;
                 }
                 //println!("{} {} sym {}", stack.len(), if catch { "catch" } else if ret { "ret" } else { "call" }, json_name(self.sym_cache.get(&addr).unwrap()));
@@ -359,53 +696,87 @@ impl TraceConverter {
                     //is probably less bad than leaving it as is since then it would keep growing with
                     //every catch
                     //
-                    //TODO: we could probably improve the handling of "uninstrumented catchers" by keeping
-                    //a history of the fully-popped stacks and then when a return arrives of a function
-                    //in one of these stacks that was "orphaned" by the throw/catch, we could find its call
-                    //entry in this history and reconstruct the call sequence. this could be done given demand;
-                    //ATM we just advise against compiling "catchers" without instrumentation. [note that
-                    //the improvement above would work some of the time but not always, eg because a return
-                    //of any of the catcher's caller wasn't traced, either because it didn't happen or
-                    //because the callers of the catcher were also uninstrumented - and this isn't a far-fetched
-                    //scenario, eg if you have some loop with the top-level code catching exceptions,
-                    //it might be running "indefinitely" so you won't see a return that would trigger the
-                    //logic above. so advising against uninstrumented catchers
-                    //will remain valid even if we add all the logic described above.]
-                    let catcher = self.sym_cache.get(&addr).unwrap().demangled_func.clone();
-                    let mut unwound = 0;
-                    while !stack.is_empty() {
-                        let last = stack.last().unwrap();
-                        if bit_set(last.address, CALL_RETURNING_UPON_THROW_BIT) {
-                            //this was traced with -finstrument-functions or "something" that would have
-                            //recorded a return event had it been returned from due to stack unwinding
-                            break;
+                    //to improve the handling of "uninstrumented catchers", we keep a history of the fully-popped
+                    //stacks, and when a return arrives of a function in one of these stacks that was "orphaned"
+                    //by the throw/catch, we find its call entry in this history and reconstruct the call sequence
+                    //(see the handling of orphan returns below.) [note that this works some of the time but not
+                    //always, eg because a return of any of the catcher's caller wasn't traced, either because it
+                    //didn't happen or because the callers of the catcher were also uninstrumented - and this isn't
+                    //a far-fetched scenario, eg if you have some loop with the top-level code catching exceptions,
+                    //it might be running "indefinitely" so you won't see a return that would trigger the recovery.
+                    //so advising against uninstrumented catchers remains valid.]
+                    let catcher_sym = self.sym_cache.get(&addr).unwrap().clone();
+                    let catcher = &catcher_sym.demangled_func;
+                    let num_unwound = stack.iter().rev().position(|frame| {
+                        //a function traced with -finstrument-functions or "something" that would have
+                        //recorded a return event had it been returned from due to stack unwinding
+                        bit_set(frame.address, CALL_RETURNING_UPON_THROW_BIT) ||
+                        //we don't compare by address since it could be two different symbols - we entered
+                        //"f(int)" and we are catching inside "f(int) [clone .cold]"; procaddr2sym strips
+                        //the [clone...] from the name so we can compare by it
+                        self.sym_cache.get(&(frame.address & ADDRESS_MASK)).unwrap().demangled_func == *catcher
+                    }).unwrap_or(stack.len());
+                    let first_unwound = stack.len() - num_unwound;
+                    *catches.entry(json_name(&catcher_sym)).or_default() += 1;
+                    let catch_site = self.inline_breadcrumb(addr);
+                    let returns = returns_since_call.drain(..).map(|(depth, sym)| (depth, json_name(&sym))).collect();
+                    let catch = CatchEvent { catcher_sym, cycle: entry.cycle, returns, catch_site };
+                    quality.catches += 1;
+                    if first_unwound == 0 && !stack.is_empty() {
+                        unwound_stacks.push((mem::take(&mut stack), catch));
+                        if unwound_stacks.len() > MAX_UNWOUND_STACKS {
+                            let (frames, catch) = unwound_stacks.remove(0);
+                            self.write_unwound_frames(&mut json, &frames, 0, &catch, &thread_trace.thread_id, &mut funcset)?;
+                            quality.unwound_by_catches += frames.len();
                         }
-                        let call_sym = self.sym_cache.get(&(last.address & ADDRESS_MASK)).unwrap();
-                        if catcher == call_sym.demangled_func { //we don't compare by address since it could be two
-                            //different symbols - we entered "f(int)" and we are catching inside "f(int) [clone .cold]";
-                            //procaddr2sym strips the [clone...] from the name so we can compare by it
-                            break;
-                        }
-                        //these all end at the same cycle contrary to the JSON spec's perfect nesting requirement;
-                        //unlike XRay we try to make them stand apart by 1 ns (the timeline's precision), also makes testing more straightforward
-                        unwound += 1;
-                        self.write_function_call_event(&mut json, &call_sym.clone(), last.cycle, entry.cycle, unwound, &thread_trace.thread_id, &mut funcset)?;
-                        stack.pop();
+                    }
+                    else {
+                        self.write_unwound_frames(&mut json, &stack, first_unwound, &catch, &thread_trace.thread_id, &mut funcset)?;
+                        quality.unwound_by_catches += num_unwound;
+                        stack.truncate(first_unwound);
                     }
                     continue;
                 }
                 if !ret {
                     stack.push(*entry);
+                    returns_since_call.clear();
                 }
                 else {
                     let ret_sym = self.sym_cache.get(&addr).unwrap().clone();
 
+                    if stack.is_empty() && !ret_with_caller_addr && !unwound_stacks.is_empty() {
+                        //look for the returning function in the stacks emptied by catches in uninstrumented functions,
+                        //the most recent first. if found, the frames above it were unwound by the catch, while it and its
+                        //callers kept running, with the catcher presumably called by it. (we don't try this with returns
+                        //logged with the caller's address, since we don't know which function returned)
+                        let func = |frame: &FunTraceEntry| &self.sym_cache.get(&(frame.address & ADDRESS_MASK)).unwrap().demangled_func;
+                        let found = unwound_stacks.iter().enumerate().rev().find_map(|(h, (frames, _))| {
+                            frames.iter().rposition(|frame| *func(frame) == ret_sym.demangled_func).map(|i| (h, i))
+                        });
+                        if let Some((h, i)) = found {
+                            for (frames, catch) in unwound_stacks.split_off(h+1) {
+                                self.write_unwound_frames(&mut json, &frames, 0, &catch, &thread_trace.thread_id, &mut funcset)?;
+                                quality.unwound_by_catches += frames.len();
+                            }
+                            //the catch unwound only the frames above the recovered ones
+                            let (mut frames, catch) = unwound_stacks.pop().unwrap();
+                            self.write_unwound_frames(&mut json, &frames, i+1, &catch, &thread_trace.thread_id, &mut funcset)?;
+                            quality.unwound_by_catches += frames.len() - (i+1);
+                            frames.truncate(i+1);
+                            println!("      recovered {} and its {} callers from the stack emptied by the catch at cycle {} (the exception was presumably caught by an uninstrumented function)",
+                                     json_name(self.sym_cache.get(&(frames[i].address & ADDRESS_MASK)).unwrap()), i, catch.cycle-self.time_base);
+                            quality.recovered_calls += frames.len();
+                            self.recovered_call_cycles.extend(frames.iter().map(|frame| frame.cycle));
+                            stack = frames;
+                        }
+                    }
                     if stack.is_empty() { //an "orphan return" - the call wasn't in the trace
                         num_orphan_returns += 1;
                         //if ret_with_caller_addr, record the return into the function we're expecting to return into (might be unknown
                         //or we could know by getting a previous return event with the caller's address)
                         let sym = if ret_with_caller_addr { &expecting_to_return_into_sym } else { &ret_sym };
-                        self.write_function_call_event(&mut json, sym, earliest_cycle, entry.cycle, -num_orphan_returns, &thread_trace.thread_id, &mut funcset)?;
+                        self.write_function_call_event(&mut json, &DecodedCall { sym, call_cycle: earliest_cycle, return_cycle: entry.cycle, extra_ns: -num_orphan_returns, callers: &[] }, &thread_trace.thread_id, &mut funcset)?;
+                        self.write_anomaly_event(&mut json, "orphan return", entry.cycle, &thread_trace.thread_id, &format!(r#""function":{}"#, json_name(sym)))?;
                         if ret_with_caller_addr {
                             expecting_to_return_into_sym = ret_sym.clone();
                         }
@@ -432,9 +803,11 @@ impl TraceConverter {
                         //call one clone and return into another but who knows, certainly catch returns to another clone at times
                         if ret_sym.demangled_func != call_sym.demangled_func {
                             println!("      WARNING: call/return mismatch - {} popped from the stack but {} returning", json_name(&call_sym), json_name(&ret_sym));
+                            let expected = json_name(&call_sym);
+                            let mut popped: Vec<String> = Vec::new();
                             let mut found = false;
                             while !found {
-                                self.write_function_call_event(&mut json, &call_sym.clone(), call_cycle, entry.cycle, returns, &thread_trace.thread_id, &mut funcset)?;
+                                self.write_function_call_event(&mut json, &DecodedCall { sym: &call_sym, call_cycle, return_cycle: entry.cycle, extra_ns: returns, callers: &stack }, &thread_trace.thread_id, &mut funcset)?;
                                 if stack.is_empty() {
                                     break;
                                 }
@@ -442,19 +815,30 @@ impl TraceConverter {
                                 call_sym = self.sym_cache.get(&(last.address & ADDRESS_MASK)).unwrap().clone();
                                 call_cycle = last.cycle;
                                 println!("        WARNING: popping {}", json_name(&call_sym));
+                                popped.push(json_name(&call_sym));
                                 stack.pop();
                                 returns += 1;
                                 found = ret_sym.demangled_func == call_sym.demangled_func;
                             }
+                            quality.mismatches += 1;
+                            quality.popped_frames += popped.len();
+                            self.write_anomaly_event(&mut json, "call/return mismatch", entry.cycle, &thread_trace.thread_id,
+                                &format!(r#""expected":{},"returning":{},"popped":[{}]"#, expected, json_name(&ret_sym), popped.join(",")))?;
                         }
                     }
                     else if !stack.is_empty() {
                         let ret_caller_sym = self.sym_cache.get(&(stack.last().unwrap().address & ADDRESS_MASK)).unwrap();
                         if ret_sym.demangled_func != ret_caller_sym.demangled_func && stack.iter().any(|&entry| self.sym_cache.get(&(entry.address & ADDRESS_MASK)).unwrap().demangled_func == ret_sym.demangled_func) {
-                            println!("      WARNING: call/return mismatch - {} called from {}, the returning function's caller is {}", json_name(&call_sym), json_name(ret_caller_sym), json_name(&ret_sym));
+                            //the logged address is the return address into the caller, so it tells the line of the call
+                            let return_address = self.procaddr2sym.proc_addr2line(addr - 1);
+                            let call_line = Value::String(format!("{}:{}", return_address.file, return_address.line)).to_string();
+                            let ret_caller_sym = self.sym_cache.get(&(stack.last().unwrap().address & ADDRESS_MASK)).unwrap();
+                            println!("      WARNING: call/return mismatch - {} called from {}, the returning function's caller is {} (returning to {})", json_name(&call_sym), json_name(ret_caller_sym), json_name(&ret_sym), call_line);
+                            let expected = json_name(ret_caller_sym);
+                            let mut popped: Vec<String> = Vec::new();
                             let mut found = false;
                             while !found {
-                                self.write_function_call_event(&mut json, &call_sym.clone(), call_cycle, entry.cycle, returns, &thread_trace.thread_id, &mut funcset)?;
+                                self.write_function_call_event(&mut json, &DecodedCall { sym: &call_sym, call_cycle, return_cycle: entry.cycle, extra_ns: returns, callers: &stack }, &thread_trace.thread_id, &mut funcset)?;
                                 if stack.is_empty() {
                                     break;
                                 }
@@ -462,56 +846,184 @@ impl TraceConverter {
                                 call_sym = self.sym_cache.get(&(last.address & ADDRESS_MASK)).unwrap().clone();
                                 call_cycle = last.cycle;
                                 println!("        WARNING: popping {}", json_name(&call_sym));
+                                popped.push(json_name(&call_sym));
                                 stack.pop();
                                 returns += 1;
                                 found = !stack.is_empty() && ret_sym.demangled_func == self.sym_cache.get(&(stack.last().unwrap().address & ADDRESS_MASK)).unwrap().demangled_func;
                             }
+                            quality.mismatches += 1;
+                            quality.popped_frames += popped.len();
+                            self.write_anomaly_event(&mut json, "call/return mismatch", entry.cycle, &thread_trace.thread_id,
+                                &format!(r#""expected_caller":{},"returning_into":{},"returning_to":{},"popped":[{}]"#, expected, json_name(&ret_sym), call_line, popped.join(",")))?;
+                        }
+                    }
+                    //with some instrumentation methods, a function tail-calling another logs its return before jumping
+                    //to the callee; if the disassembly says it's a tail call, we keep the returning function on the stack
+                    //as the callee's caller, and close it when the callee returns
+                    if self.fix_tail_calls && returns == 0 && !ret_with_caller_addr {
+                        if let Some(next) = entries.get(entry_index+1).filter(|next| next.address & CATCH_MASK == 0) {
+                            if self.tail_calls(&call_sym, next.address & ADDRESS_MASK) {
+                                stack.push(call_entry);
+                                tail_callers.insert(call_entry.cycle);
+                                continue;
+                            }
                         }
                     }
-                    self.write_function_call_event(&mut json, &call_sym, call_cycle, entry.cycle, returns, &thread_trace.thread_id, &mut funcset)?;
+                    if ret_with_caller_addr {
+                        //the logged address is the return address into the caller
+                        self.call_site = self.inline_breadcrumb(addr);
+                    }
+                    self.write_function_call_event(&mut json, &DecodedCall { sym: &call_sym, call_cycle, return_cycle: entry.cycle, extra_ns: returns, callers: &stack }, &thread_trace.thread_id, &mut funcset)?;
+                    //functions without the bit can't log returns during the unwinding, so they returned normally
+                    //(and after a call/return mismatch, call_sym isn't the function of call_entry)
+                    if returns == 0 && bit_set(call_entry.address, CALL_RETURNING_UPON_THROW_BIT) {
+                        returns_since_call.push((stack.len()+1, call_sym));
+                    }
+                    //close the functions which returned before tail-calling the function that just returned
+                    while stack.last().is_some_and(|top| tail_callers.remove(&top.cycle)) {
+                        let tail_caller = stack.pop().unwrap();
+                        let tail_caller_sym = self.sym_cache.get(&(tail_caller.address & ADDRESS_MASK)).unwrap().clone();
+                        returns += 1;
+                        self.write_function_call_event(&mut json, &DecodedCall { sym: &tail_caller_sym, call_cycle: tail_caller.cycle, return_cycle: entry.cycle, extra_ns: returns, callers: &stack }, &thread_trace.thread_id, &mut funcset)?;
+                        quality.tail_calls += 1;
+                    }
                 }
             }
+            for (frames, catch) in mem::take(&mut unwound_stacks) {
+                self.write_unwound_frames(&mut json, &frames, 0, &catch, &thread_trace.thread_id, &mut funcset)?;
+                quality.unwound_by_catches += frames.len();
+            }
             //if the stack isn't empty, record a call with a fake return cycle
+            if !stack.is_empty() {
+                let running: Vec<String> = stack.iter().map(|entry| json_name(self.sym_cache.get(&(entry.address & ADDRESS_MASK)).unwrap())).collect();
+                self.write_anomaly_event(&mut json, "no return logged", latest_cycle, &thread_trace.thread_id, &format!(r#""functions":[{}]"#, running.join(",")))?;
+            }
+            quality.fake_returns = stack.len();
+            quality.orphan_returns = num_orphan_returns as usize;
             let mut fake_returns = stack.len() as i32;
-            for entry in &stack {
+            for (i, entry) in stack.iter().enumerate() {
                  let call_sym = self.sym_cache.get(&(entry.address & ADDRESS_MASK)).unwrap();
-                 self.write_function_call_event(&mut json, &call_sym.clone(), entry.cycle, latest_cycle, fake_returns, &thread_trace.thread_id, &mut funcset)?;
+                 self.write_function_call_event(&mut json, &DecodedCall { sym: &call_sym.clone(), call_cycle: entry.cycle, return_cycle: latest_cycle, extra_ns: fake_returns, callers: &stack[..i] }, &thread_trace.thread_id, &mut funcset)?;
                  fake_returns -= 1;
             }
-            let name = String::from_utf8(thread_trace.thread_id.name.iter().filter(|&&x| x != 0 as u8).copied().collect()).unwrap();
+            let name = thread_name(&thread_trace.thread_id);
+            if self.grep.is_some() {
+                continue;
+            }
             if latest_cycle >= earliest_cycle {
                 println!("  thread {} {} - {} recent function calls logged over {} cycles [{} - {}]", thread_trace.thread_id.tid, name, self.num_events, latest_cycle-earliest_cycle, earliest_cycle-self.time_base, latest_cycle-self.time_base);
+                if thread_trace.wrapped && earliest_cycle == entries[0].cycle {
+                    println!("    the trace buffer wrapped around - calls before cycle {} were overwritten", earliest_cycle-self.time_base);
+                }
+                if quality.tail_calls > 0 {
+                    println!("    {} calls kept running until the return of the function they tail-called", quality.tail_calls);
+                }
+                if quality.recovered_calls > 0 {
+                    println!("    {} calls recovered from stacks emptied by catches in uninstrumented functions", quality.recovered_calls);
+                }
             }
             else {
                 println!("    skipping thread {} {} (all {} logged function entry/return events are too old)", thread_trace.thread_id.tid, name, entries.len());
             }
+            let window = (earliest_cycle.saturating_sub(self.time_base), latest_cycle.saturating_sub(self.time_base));
+            threads_quality.push(quality.to_json(thread_trace, self.num_events, window));
         }
-        if self.dry {
+        if self.grep.is_none() {
+            //the instrumentation methods of the binaries whose functions appear in the sample, reported
+            //once per binary; mixing methods in one process is untested and weakens the decoding heuristics
+            let mut executables: Vec<&String> = funcset.iter().map(|sym| &sym.executable_file).filter(|file| *file != "??").collect();
+            executables.sort();
+            executables.dedup();
+            let mut methods: Vec<Instrumentation> = Vec::new();
+            for executable in executables {
+                let exe_methods = self.procaddr2sym.instrumentation(executable);
+                if self.reported_instrumentation.insert(executable.clone()) {
+                    let names: Vec<String> = exe_methods.iter().map(|method| method.to_string()).collect();
+                    println!("  {} instrumentation: {}", executable, if names.is_empty() { "not found".to_string() } else { names.join(", ") });
+                }
+                instrumentation.push(serde_json::json!({"executable_file": executable, "methods": exe_methods.iter().map(|method| method.to_string()).collect::<Vec<String>>()}));
+                methods.extend(exe_methods);
+            }
+            methods.sort();
+            methods.dedup();
+            if methods.len() > 1 {
+                let names: Vec<String> = methods.iter().map(|method| method.to_string()).collect();
+                println!("  WARNING: the traced binaries use different instrumentation methods ({}) - this wasn't tested and the decoding might be less accurate", names.join(", "));
+            }
+        }
+        if self.grep.is_none() && !catches.is_empty() {
+            let mut catches: Vec<(String, u64)> = catches.into_iter().collect();
+            catches.sort_by_key(|(catcher, count)| (std::cmp::Reverse(*count), catcher.clone()));
+            println!("  exceptions caught:");
+            for (catcher, count) in catches {
+                println!("    {} {}", count, catcher);
+            }
+        }
+        if !self.writes_json() {
             return Ok(())
         }
+        if let Some(ref mut recorded_calls) = self.recorded_calls {
+            recorded_calls.index();
+        }
+        if self.quality_report {
+            let report = fname.strip_suffix(".json").unwrap_or(fname).to_string() + ".quality.json";
+            let quality = serde_json::json!({"sample": fname, "instrumentation": instrumentation, "threads": threads_quality});
+            File::create(&report)?.write_all(format!("{:#}\n", quality).as_bytes())?;
+            println!("  decode quality summary written to {}", report);
+        }
     
-        json.write(b"],\n")?;
+        if self.wakeup_flows {
+            self.write_wakeup_flows(&mut json, sample_entries, oldest)?;
+        }
+
+        if self.cpu_tracks {
+            self.write_cpu_tracks(&mut json, sample_entries, oldest)?;
+        }
+
+        json.write_all(b"],\n")?;
 
         if !ftrace_events.is_empty() {
-            let joined: String = ftrace_events.iter().map(|e| e.line.clone() + "\n").collect();
+            let rat = |n: u64| Ratio::from_u64(n).unwrap();
+            //ftrace timestamps are supposed to be in seconds; CPU frequency is in TSC cycles per second;
+            //so dividing by frequency will convert TSC to seconds. Perfetto timeline accuracy is ns
+            //hence 10 digits after '.' (9 plus another to make sure different cycles don't become the same ns)
+            let cycles_per_second = rat(self.cpu_freq);
+            let fixts = |ts: u64| rat2dec(&(rat(ts-self.time_base)/cycles_per_second.clone()), 10);
+            let joined: String = ftrace_events.iter().map(|e| e.line_with_timestamp(&fixts(e.timestamp)) + "\n").collect();
 
-            json.write(br#""systemTraceEvents": "#)?;
+            json.write_all(br#""systemTraceEvents": "#)?;
             //# tracer: nop is something Perfetto doesn't seem to need but the Chromium trace
             //JSON spec insists is a must
-            json.write(Value::String("# tracer: nop\n".to_string() + &joined).to_string().as_bytes())?;
-            json.write(b",\n")?;
+            json.write_all(Value::String("# tracer: nop\n".to_string() + &joined).to_string().as_bytes())?;
+            json.write_all(b",\n")?;
 
             let oldest_ftrace = ftrace_events[0].timestamp;
             let newest_ftrace = ftrace_events[ftrace_events.len()-1].timestamp;
             println!("  ftrace - {} events logged over {} cycles [{} - {}]", ftrace_events.len(), newest_ftrace-oldest_ftrace, oldest_ftrace-self.time_base, newest_ftrace-self.time_base);
         }
 
+        if let (true, Some(sched)) = (self.off_cpu_report, &self.sched) {
+            let report = fname.strip_suffix(".json").unwrap_or(fname).to_string() + ".offcpu.txt";
+            sched.write_report(&report, self.cpu_freq)?;
+            println!("  off-CPU time per function written to {}", report);
+        }
+        if let (Some(critical_path), Some(sched), Some(recorded_calls)) = (&self.critical_path, &self.sched, &self.recorded_calls) {
+            let report = fname.strip_suffix(".json").unwrap_or(fname).to_string() + ".critpath.txt";
+            let thread_names = sample_entries.iter().map(|t| (t.thread_id.tid, thread_name(&t.thread_id))).collect();
+            if critical_path.write_report(&report, sched, recorded_calls, &thread_names, self.cpu_freq, self.time_base)? {
+                println!("  critical path written to {}", report);
+            }
+            else {
+                println!("  no calls of functions matching the --critical-path regex found");
+            }
+        }
+
         // find the source files containing the functions in this sample's set
         let mut fileset: HashSet<String> = HashSet::new();
         for sym in funcset.iter() {
             fileset.insert(sym.file.clone());
         }
-        json.write(br#""viztracer_metadata": {
+        json.write_all(br#""viztracer_metadata": {
   "version": "0.16.3",
   "overflow": false,
   "producer": "funtrace2viz"
@@ -522,33 +1034,38 @@ impl TraceConverter {
     
         // dump the source code of these files into the json
         for (i, file) in fileset.iter().enumerate() {
-            if let Some(&ref source_code) = self.source_cache.get(file) {
-                json.write(Value::String(file.clone()).to_string().as_bytes())?;
-                json.write(b":[")?;
-                json.write(source_code.json_str.as_bytes())?;
-                json.write(b",")?;
-                json.write(format!("{}", source_code.num_lines).as_bytes())?;
-                json.write(if i==fileset.len()-1 { b"]\n" } else { b"],\n" })?;
+            if let Some(source_code) = self.source_cache.get(file) {
+                json.write_all(Value::String(file.clone()).to_string().as_bytes())?;
+                json.write_all(b":[")?;
+                json.write_all(source_code.json_str.as_bytes())?;
+                json.write_all(b",")?;
+                json.write_all(format!("{}", source_code.num_lines).as_bytes())?;
+                json.write_all(if i==fileset.len()-1 { b"]\n" } else { b"],\n" })?;
             }
         }
-        json.write(br#"},
+        json.write_all(br#"},
 "functions": {
 "#)?;
     
         // tell where each function is defined
         for (i, sym) in funcset.iter().enumerate() {
-            // line-3 is there to show the function prototype in vizviewer/Perfetto
-            // (often the debug info puts the line at the opening { of a function
-            // and then the prototype is not seen, it can also span a few lines)
-            json.write(format!("{}:[{},{}]{}\n", json_name(sym), Value::String(sym.file.clone()).to_string(), if sym.line <= 3 { sym.line } else { sym.line-3 }, if i==funcset.len()-1 { "" } else { "," }).as_bytes())?;
+            // we point at the function's declaration line from the debug info, so that the prototype is visible
+            // in vizviewer/Perfetto (sym.line is often the opening { of a function or some line inside it.)
+            // without it, line-3 is a guess which shows most single-line prototypes
+            let line = match self.procaddr2sym.function_lines(sym) {
+                Some(lines) if lines.decl_line > 0 && lines.decl_line <= sym.line && lines.decl_file == sym.file => lines.decl_line,
+                _ => if sym.line <= 3 { sym.line } else { sym.line-3 },
+            };
+            json.write_all(format!("{}:[{},{}]{}\n", json_name(sym), Value::String(sym.file.clone()), line, if i==funcset.len()-1 { "" } else { "," }).as_bytes())?;
         }
-        json.write(b"}}}\n")?;
+        json.write_all(b"}}}\n")?;
     
         Ok(())
     }
 
-    pub fn parse_chunks(&mut self, file_path: &String, json_basename: &String) -> io::Result<()> {
-        let mut file = File::open(file_path)?;
+    //json_basename is only None with --dry or --grep, which don't create JSON files
+    pub fn parse_chunks(&mut self, file_path: &String, json_basename: Option<&str>) -> io::Result<()> {
+        let mut file = BufReader::new(File::open(file_path)?);
     
         let mut sample_entries: Vec<ThreadTrace> = Vec::new();
         let mut num_json = 0;
@@ -559,41 +1076,26 @@ impl TraceConverter {
 
         self.procaddr2sym.input_source = Some(procaddr2sym::input_source(file_path.clone()));
 
-        loop {
-            //the file consists of chunks with an 8-byte magic string telling the chunk
-            //type, followed by an 8-byte length field and then contents of that length
-            let mut magic = [0u8; MAGIC_LEN];
-            if file.read_exact(&mut magic).is_err() {
-                break; // End of file
-            }
-    
-            let mut length_bytes = [0u8; LENGTH_LEN];
-            file.read_exact(&mut length_bytes)?;
-            let chunk_length = usize::from_ne_bytes(length_bytes);
-    
+        while let Some(Chunk { magic, content }) = read_chunk(&mut file)? {
+            let chunk_length = content.len();
             if &magic == b"FUNTRACE" {
                 if chunk_length != 8 {
                     println!("warning: unexpected length {} for FUNTRACE chunk", chunk_length);
-                    file.seek(SeekFrom::Current(chunk_length as i64))?;
                     continue;
                 }
-                let mut freq_bytes = [0u8; 8];
-                file.read_exact(&mut freq_bytes)?;
-                self.cpu_freq = u64::from_ne_bytes(freq_bytes);
+                self.cpu_freq = u64::from_ne_bytes(content.as_slice().try_into().unwrap());
             }
             else if &magic == b"CMD LINE" {
-                let mut cmd_bytes = vec![0u8; chunk_length];
-                file.read_exact(&mut cmd_bytes)?;
-                self.cmd_line = String::from_utf8(cmd_bytes).unwrap();
+                self.cmd_line = String::from_utf8(content).unwrap();
             }
             else if &magic == b"ENDTRACE" {
                 if chunk_length != 0 {
                     println!("warning: non-zero length for ENDTRACE chunk");
-                    file.seek(SeekFrom::Current(chunk_length as i64))?;
                     continue;
                 }
                 if !sample_entries.is_empty() || !ftrace_text.is_empty() {
                     if self.samples.is_empty() || self.samples.contains(&num_json) {
+                        self.sample_num = num_json;
                         self.write_sample_to_json(&format_json_filename(json_basename, num_json), &sample_entries, &ftrace_text)?;
                     }
                     else {
@@ -607,45 +1109,55 @@ impl TraceConverter {
             else if &magic == b"PROCMAPS" {
                 //the content of the dumping process's /proc/self/maps to use when
                 //interpreting the next trace samples (until another PROCMAPS chunk is encountered)
-                let mut chunk_content = vec![0u8; chunk_length];
-                file.read_exact(&mut chunk_content)?;
-                self.procaddr2sym.set_proc_maps(chunk_content.as_slice());
+                self.procaddr2sym.set_proc_maps(content.as_slice());
                 //the symbol cache might have been invalidated if the process unloaded and reloaded a shared object
                 self.sym_cache = HashMap::new();
+            } else if &magic == b"BUILDIDS" {
+                //the build IDs of the objects in the last PROCMAPS chunk, to check the binaries we find against
+                self.procaddr2sym.set_build_ids(content.as_slice());
+            } else if &magic == b"SYMTABLE" {
+                //the symbols of the traced addresses resolved by funsymbolize where the trace was taken,
+                //used instead of the binaries
+                self.procaddr2sym.set_symtable(content.as_slice());
             } else if &magic == b"THREADID" {
                 if chunk_length != std::mem::size_of::<ThreadID>() {
                     println!("Unexpected THREAD chunk length {} - expecting {}", chunk_length, std::mem::size_of::<ThreadID>());
-                    file.seek(SeekFrom::Current(chunk_length as i64))?;
                     continue;
                 }
 
-                file.read_exact(bytemuck::bytes_of_mut(&mut thread_id))?;
+                thread_id = bytemuck::pod_read_unaligned(&content);
             } else if &magic == b"TRACEBUF" {
                 if chunk_length % mem::size_of::<FunTraceEntry>() != 0 {
                     println!("Invalid TRACEBUF chunk length {} - must be a multiple of {}", chunk_length, mem::size_of::<FunTraceEntry>());
-                    file.seek(SeekFrom::Current(chunk_length as i64))?;
                     continue;
                 }
     
-                let num_entries = chunk_length / mem::size_of::<FunTraceEntry>();
-                let mut entries = ThreadTrace { thread_id, trace: vec![FunTraceEntry { address: 0, cycle: 0 }; num_entries] };
-                file.read_exact(bytemuck::cast_slice_mut(&mut entries.trace))?;
+                let mut entries = ThreadTrace { thread_id, trace: content.chunks_exact(mem::size_of::<FunTraceEntry>()).map(bytemuck::pod_read_unaligned).collect(), wrapped: false, stale_entries: 0 };
                 entries.trace.retain(|&entry| !(entry.cycle == 0 && entry.address == 0));
+                let (stale, wraparounds) = drop_stale_entries(&mut entries.trace);
+                if !stale.is_empty() {
+                    let cycles: Vec<String> = stale.iter().take(8).map(|entry| entry.cycle.to_string()).collect();
+                    println!("WARNING: thread {} - dropped {} stale trace entries left by the racy writeout of the cyclic buffer (cycles {}{})",
+                             thread_id.tid, stale.len(), cycles.join(", "), if stale.len() > cycles.len() { ", ..." } else { "" });
+                }
+                if wraparounds > 1 {
+                    println!("WARNING: thread {} - the trace buffer seems to wrap around {} times", thread_id.tid, wraparounds);
+                }
+                entries.wrapped = wraparounds > 0;
+                entries.stale_entries = stale.len();
                 if !entries.trace.is_empty() {
                     entries.trace.sort_by_key(|entry| entry.cycle);
                     sample_entries.push(entries);
                 }
             } else if &magic == b"FTRACETX" {
-                let mut ftrace_bytes = vec![0u8; chunk_length];
-                file.read_exact(&mut ftrace_bytes)?;
-                ftrace_text = String::from_utf8(ftrace_bytes).unwrap();
+                ftrace_text = String::from_utf8(content).unwrap();
             } else {
                 println!("Unknown chunk type: {:?}", std::str::from_utf8(&magic).unwrap_or("<invalid>"));
-                file.seek(SeekFrom::Current(chunk_length as i64))?;
             }
         }
         if !sample_entries.is_empty() || !ftrace_text.is_empty() {
             println!("warning: FUNTRACE block not closed by ENDTRACE");
+            self.sample_num = num_json;
             self.write_sample_to_json(&format_json_filename(json_basename, num_json), &sample_entries, &ftrace_text)?;
         }
     
@@ -653,7 +1165,8 @@ impl TraceConverter {
     }
 }
 
-fn format_json_filename(basename: &String, number: u32) -> String {
+fn format_json_filename(basename: Option<&str>, number: u32) -> String {
+    let Some(basename) = basename else { return format!("#{}", number) };
     if number > 0 {
         format!("{}.{}.json", basename, number)
     } else {
@@ -661,6 +1174,10 @@ fn format_json_filename(basename: &String, number: u32) -> String {
     }
 }
 
+fn thread_name(thread_id: &ThreadID) -> String {
+    String::from_utf8(thread_id.name.iter().filter(|&&x| x != 0_u8).copied().collect()).unwrap()
+}
+
 static mut PRINT_BIN_INFO: bool = false;
 
 fn json_name(sym: &SymInfo) -> String {
@@ -684,6 +1201,192 @@ fn main() -> io::Result<()> {
         PRINT_BIN_INFO = args.executable_file_info;
     }
     let mut convert = TraceConverter::new(&args);
-    convert.parse_chunks(&args.funtrace_raw, &args.out_basename)
+    if args.grep.is_some() {
+        //we only print function names, so there's no need to look up file:line info in the DWARF
+        convert.procaddr2sym.find_source_lines = false;
+    }
+    convert.parse_chunks(&args.funtrace_raw, args.out_basename.as_deref())
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use offcpu::tests::FTRACE;
+
+    //main (10) wakes worker (20) up after the worker blocks at the end of FTRACE
+    const MORE_FTRACE: &str = "
+            main-10      [001] d.... 310: sched_waking: comm=worker pid=20 prio=120 target_cpu=000
+          <idle>-0       [000] d.... 330: sched_switch: prev_comm=swapper/0 prev_pid=0 prev_prio=120 prev_state=R ==> next_comm=worker next_pid=20 next_prio=120
+";
+
+    fn thread(pid: u64, tid: u64, name: &str) -> ThreadTrace {
+        let mut thread_name = [0u8; 16];
+        thread_name[..name.len()].copy_from_slice(name.as_bytes());
+        ThreadTrace { thread_id: ThreadID { pid, tid, name: thread_name }, trace: Vec::new(), wrapped: false, stale_entries: 0 }
+    }
+
+    fn converter(flags: &[&str], ftrace: &str) -> TraceConverter {
+        let mut converter = TraceConverter::new(&Cli::parse_from([&["funtrace2viz", "funtrace.raw", "out"], flags].concat()));
+        converter.cpu_freq = 1000000;
+        converter.sched = Some(OffCpuStats::new(&parse_ftrace_lines(ftrace)));
+        converter.recorded_calls = Some(CallRecorder::default());
+        converter
+    }
+
+    fn sym(name: &str) -> SymInfo {
+        SymInfo { func: name.to_string(), demangled_func: name.to_string(), file: "a.cpp".to_string(), line: 1, executable_file: "a.out".to_string(), static_addr: 0, size: 0 }
+    }
+
+    //the JSON events written by write_events
+    fn json_events(write_events: impl FnOnce(&mut File) -> io::Result<()>) -> Vec<Value> {
+        let path = std::env::temp_dir().join(format!("funtrace2viz-test-{}-{:?}.json", std::process::id(), std::thread::current().id()));
+        write_events(&mut File::create(&path).unwrap()).unwrap();
+        let json = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        json.lines().filter(|line| !line.is_empty()).map(|line| serde_json::from_str(line.trim_start_matches(',')).unwrap()).collect()
+    }
+
+
+    //a TRACEBUF in the order of the buffer, with the entries' addresses telling them apart
+    fn tracebuf(cycles: &[u64]) -> Vec<FunTraceEntry> {
+        cycles.iter().enumerate().map(|(i, &cycle)| FunTraceEntry { address: i as u64 + 1, cycle }).collect()
+    }
+
+    fn cycles(entries: &[FunTraceEntry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.cycle).collect()
+    }
+
+    #[test]
+    fn unwrapped_buffer() {
+        let mut trace = tracebuf(&[10, 20, 30, 40]);
+        let (stale, wraparounds) = drop_stale_entries(&mut trace);
+        assert!(stale.is_empty());
+        assert_eq!(wraparounds, 0);
+        assert_eq!(cycles(&trace), [10, 20, 30, 40]);
+    }
+
+    #[test]
+    fn wrapped_buffer() {
+        let mut trace = tracebuf(&[50, 60, 70, 20, 30, 40]);
+        let (stale, wraparounds) = drop_stale_entries(&mut trace);
+        assert!(stale.is_empty());
+        assert_eq!(wraparounds, 1);
+        assert_eq!(cycles(&trace), [50, 60, 70, 20, 30, 40]);
+    }
+
+    #[test]
+    fn new_old_new() {
+        //the entry before the newest one didn't make it into the buffer, leaving the one from the previous lap
+        let mut trace = tracebuf(&[50, 60, 5, 80, 20, 30, 40]);
+        let (stale, wraparounds) = drop_stale_entries(&mut trace);
+        assert_eq!(cycles(&stale), [5]);
+        assert_eq!(wraparounds, 1);
+        assert_eq!(cycles(&trace), [50, 60, 80, 20, 30, 40]);
+    }
+
+    #[test]
+    fn stale_at_the_start_of_the_buffer() {
+        let mut trace = tracebuf(&[5, 6, 60, 70, 20, 30, 40]);
+        let (stale, wraparounds) = drop_stale_entries(&mut trace);
+        assert_eq!(cycles(&stale), [5, 6]);
+        assert_eq!(wraparounds, 1);
+        assert_eq!(cycles(&trace), [60, 70, 20, 30, 40]);
+    }
+
+    #[test]
+    fn stale_next_to_the_wraparound() {
+        //the entry before the newest one is stale, and so is the one after it, where the last write didn't make it
+        //into the buffer. the latter is the previous lap's entry right before the oldest one, so it continues the
+        //history without a gap and is kept as the oldest entry
+        let mut trace = tracebuf(&[50, 60, 5, 70, 25, 30, 40]);
+        let (stale, wraparounds) = drop_stale_entries(&mut trace);
+        assert_eq!(cycles(&stale), [5]);
+        assert_eq!(wraparounds, 1);
+        assert_eq!(cycles(&trace), [50, 60, 70, 25, 30, 40]);
+    }
+
+    #[test]
+    fn long_stale_run() {
+        let mut buffer: Vec<u64> = (100..110).collect();
+        buffer.extend(1..=40); //more stale entries than there are new ones around them
+        buffer.extend(200..210);
+        buffer.extend(50..60);
+        let mut trace = tracebuf(&buffer);
+        let (stale, wraparounds) = drop_stale_entries(&mut trace);
+        assert_eq!(cycles(&stale), (1..=40).collect::<Vec<u64>>());
+        assert_eq!(wraparounds, 1);
+        assert_eq!(trace.len(), 30);
+    }
+
+    #[test]
+    fn more_than_one_wraparound() {
+        //can't happen with a single cyclic buffer - the caller warns about it
+        let mut trace = tracebuf(&[50, 60, 30, 70, 20, 25]);
+        let (stale, wraparounds) = drop_stale_entries(&mut trace);
+        assert!(stale.is_empty());
+        assert_eq!(wraparounds, 2);
+    }
+
+    #[test]
+    fn wakeup_flows() {
+        let mut converter = converter(&["--wakeup-flows"], &(FTRACE.to_string() + MORE_FTRACE));
+        let threads = [thread(10, 10, "main"), thread(10, 20, "worker")];
+        //the wakeup of worker by the kernel isn't between traced threads, so the flow ids start from the one after it
+        let events = json_events(|json| converter.write_wakeup_flows(json, &threads, 0));
+        assert_eq!(events, [
+            serde_json::json!({"ph":"s","id":1,"name":"wakeup","cat":"sched","pid":10,"tid":20,"ts":200.0}),
+            serde_json::json!({"ph":"f","bp":"e","id":1,"name":"wakeup","cat":"sched","pid":10,"tid":10,"ts":260.0}),
+            serde_json::json!({"ph":"s","id":2,"name":"wakeup","cat":"sched","pid":10,"tid":10,"ts":310.0}),
+            serde_json::json!({"ph":"f","bp":"e","id":2,"name":"wakeup","cat":"sched","pid":10,"tid":20,"ts":330.0}),
+        ]);
+        //wakeups before the oldest event are left out, and the ids are those of the flows written
+        let events = json_events(|json| converter.write_wakeup_flows(json, &threads, 250));
+        let flows: Vec<(&Value, &Value, &Value)> = events.iter().map(|e| (&e["ph"], &e["id"], &e["tid"])).collect();
+        assert_eq!(flows, [(&Value::from("s"), &Value::from(1), &Value::from(10)), (&Value::from("f"), &Value::from(1), &Value::from(20))]);
+        //without the woken thread in the sample, there's nothing to draw an arrow to
+        assert!(json_events(|json| converter.write_wakeup_flows(json, &threads[..1], 0)).is_empty());
+    }
+
+    #[test]
+    fn cpu_tracks() {
+        let mut converter = converter(&["--cpu-tracks"], FTRACE);
+        let (main, worker) = (thread(10, 10, "main"), thread(10, 20, "worker"));
+        let mut funcset = HashSet::new();
+        let events = json_events(|json| {
+            for (thread, name, call_cycle, return_cycle) in [(&main, "outer", 40, 280), (&main, "inner", 120, 220), (&worker, "produce", 60, 190)] {
+                converter.first_event_in_thread = false;
+                let call = DecodedCall { sym: &sym(name), call_cycle, return_cycle, extra_ns: 0, callers: &[] };
+                converter.write_function_call_event(json, &call, &thread.thread_id, &mut funcset)?;
+            }
+            converter.recorded_calls.as_mut().unwrap().index();
+            converter.write_cpu_tracks(json, &[thread(10, 10, "main"), thread(10, 20, "worker")], 0)
+        });
+        //main ran on CPU 0 and then on CPU 1 during outer, but only on CPU 1 during inner
+        let migrations: Vec<(&Value, &Value)> = events[..3].iter().map(|e| (&e["name"], &e["args"]["cpu_migrations"])).collect();
+        assert_eq!(migrations, [(&Value::from("outer (a.cpp:1)"), &Value::from("0->1")), (&Value::from("inner (a.cpp:1)"), &Value::Null), (&Value::from("produce (a.cpp:1)"), &Value::Null)]);
+
+        //the CPUs process gets the PID after the largest traced one
+        let tracks: Vec<String> = events[3..].iter().map(|e| {
+            assert_eq!(e["pid"], 11);
+            match e["ph"].as_str().unwrap() {
+                "M" => format!("{} {}", e["name"], e["args"]["name"]),
+                _ => format!("CPU {}: {} {}-{}", e["tid"], e["name"], e["ts"], e["ts"].as_f64().unwrap() + e["dur"].as_f64().unwrap()),
+            }
+        }).collect();
+        assert_eq!(tracks, [
+            r#""thread_name" "CPU 0""#,
+            r#"CPU 0: "10 main" 20.0-50"#,
+            r#"CPU 0: "outer (a.cpp:1)" 40.0-50"#,
+            r#"CPU 0: "20 worker" 50.0-300"#,
+            r#"CPU 0: "produce (a.cpp:1)" 60.0-190"#,
+            r#""thread_name" "CPU 1""#,
+            r#"CPU 1: "10 main" 100.0-150"#,
+            r#"CPU 1: "outer (a.cpp:1)" 100.0-150"#,
+            r#"CPU 1: "inner (a.cpp:1)" 120.0-150"#,
+            r#"CPU 1: "10 main" 260.0-300"#,
+            r#"CPU 1: "outer (a.cpp:1)" 260.0-280"#,
+            r#""process_name" "CPUs""#,
+        ]);
+    }
+}
@@ -0,0 +1,311 @@
+//attributing the time threads spent switched out (according to ftrace's sched_switch
+//and sched_waking/sched_wakeup events) to the functions that were on their stacks at the time;
+//the switched out intervals & wakeups are also used for wakeup flows & critical path analysis,
+//and the CPUs threads ran on for per-CPU tracks & CPU migration annotations
+use std::collections::HashMap;
+use procaddr2sym::SymInfo;
+use std::fs::File;
+use std::io::{self, Write};
+use num::FromPrimitive;
+use num::rational::Ratio;
+use crate::ftrace::{FtraceEvent, FtraceRecord};
+use crate::rat2dec;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OffCpuState {
+    Runnable, //preempted, or woken up and waiting for a CPU
+    Blocked, //waiting for something (a lock, I/O, sleep...) before being woken up
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OffCpuInterval {
+    pub start: u64,
+    pub end: u64,
+    pub state: OffCpuState,
+    pub waker: Option<u64>, //the TID of the thread that woke us (for blocked intervals and the runnable ones following them)
+}
+
+//a thread waking up another; 0 is the kernel's idle task (as in, the wakeup came from an interrupt)
+#[derive(Debug, Clone, Copy)]
+pub struct Wakeup {
+    pub timestamp: u64,
+    pub waker: u64,
+    pub woken: u64,
+    pub resumed: Option<u64>, //when the woken thread was switched in, if we saw it
+}
+
+#[derive(Default)]
+pub struct FuncTimes {
+    pub calls: u64,
+    pub wall: u64,
+    pub runnable: u64,
+    pub blocked: u64,
+}
+
+pub struct OffCpuStats {
+    intervals: HashMap<u64, Vec<OffCpuInterval>>, //per TID, sorted by time
+    pub wakeups: Vec<Wakeup>,
+    pub comms: HashMap<u64, String>, //thread names seen in ftrace events, including those of untraced threads
+    pub cpu_runs: Vec<CpuRun>, //sorted by CPU & time, not including the idle task
+    thread_runs: HashMap<u64, Vec<CpuRun>>, //per TID, sorted by time
+    pub per_func: HashMap<SymInfo, FuncTimes>,
+}
+
+//a thread running on a CPU from start to end (according to sched_switch events)
+#[derive(Debug, Clone, Copy)]
+pub struct CpuRun {
+    pub tid: u64,
+    pub cpu: u32,
+    pub start: u64,
+    pub end: u64,
+}
+
+struct SwitchedOut {
+    at: u64,
+    blocked: bool,
+    woken: Option<(u64, u64)>, //when & by which TID
+}
+
+impl OffCpuStats {
+    pub fn new(ftrace_events: &[FtraceEvent]) -> Self {
+        let mut intervals: HashMap<u64, Vec<OffCpuInterval>> = HashMap::new();
+        let mut wakeups: Vec<Wakeup> = Vec::new();
+        let mut comms: HashMap<u64, String> = HashMap::new();
+        let mut cpu_runs: Vec<CpuRun> = Vec::new();
+        //CPU -> (TID, running since); we don't know since when a thread switched out by the first
+        //sched_switch on a CPU was running, so we say it's since the start of the ftrace data - the
+        //earliest time we know anything about (and likewise, the thread switched in by the last
+        //sched_switch on a CPU is said to run until the end of the ftrace data)
+        let start = ftrace_events.iter().map(|e| e.timestamp).min().unwrap_or(0);
+        let end = ftrace_events.iter().map(|e| e.timestamp).max().unwrap_or(0);
+        let mut running: HashMap<u32, (u64, u64)> = HashMap::new();
+        let mut switched_out: HashMap<u64, SwitchedOut> = HashMap::new();
+        //TID -> index of its latest wakeup in wakeups, until it's switched in
+        let mut pending_wakeups: HashMap<u64, usize> = HashMap::new();
+        let mut add = |tid: u64, start: u64, end: u64, state: OffCpuState, waker: Option<u64>| {
+            if end > start {
+                intervals.entry(tid).or_default().push(OffCpuInterval { start, end, state, waker });
+            }
+        };
+        let mut add_switched_out = |tid: u64, out: SwitchedOut, switched_in: u64| {
+            match (out.blocked, out.woken) {
+                (true, Some((woken, waker))) => {
+                    add(tid, out.at, woken, OffCpuState::Blocked, Some(waker));
+                    add(tid, woken, switched_in, OffCpuState::Runnable, Some(waker));
+                }
+                (true, None) => add(tid, out.at, switched_in, OffCpuState::Blocked, None),
+                (false, _) => add(tid, out.at, switched_in, OffCpuState::Runnable, None),
+            }
+        };
+
+        for event in ftrace_events {
+            comms.insert(event.pid, event.comm.clone());
+            match &event.record {
+                FtraceRecord::SchedSwitch(switch) => {
+                    comms.insert(switch.prev_pid, switch.prev_comm.clone());
+                    comms.insert(switch.next_pid, switch.next_comm.clone());
+                    if let Some(cpu) = event.cpu {
+                        let (tid, since) = running.insert(cpu, (switch.next_pid, event.timestamp)).unwrap_or((switch.prev_pid, start));
+                        cpu_runs.push(CpuRun { tid, cpu, start: since, end: event.timestamp });
+                    }
+                    //R or R+ means the thread was preempted while still runnable; anything else means
+                    //it went to sleep and will need to be woken up
+                    switched_out.insert(switch.prev_pid, SwitchedOut { at: event.timestamp, blocked: !switch.prev_state.starts_with('R'), woken: None });
+                    if let Some(off) = switched_out.remove(&switch.next_pid) {
+                        add_switched_out(switch.next_pid, off, event.timestamp);
+                    }
+                    if let Some(i) = pending_wakeups.remove(&switch.next_pid) {
+                        wakeups[i].resumed = Some(event.timestamp);
+                    }
+                }
+                FtraceRecord::SchedWakeup { pid: woken, comm } => {
+                    let (woken, waker) = (*woken, event.pid);
+                    comms.insert(woken, comm.clone());
+                    if let Some(out @ SwitchedOut { blocked: true, woken: None, .. }) = switched_out.get_mut(&woken) {
+                        out.woken = Some((event.timestamp, waker));
+                    }
+                    //sched_waking is followed by sched_wakeup for the same wakeup if both are enabled
+                    if let std::collections::hash_map::Entry::Vacant(pending) = pending_wakeups.entry(woken) {
+                        pending.insert(wakeups.len());
+                        wakeups.push(Wakeup { timestamp: event.timestamp, waker, woken, resumed: None });
+                    }
+                }
+                FtraceRecord::SchedProcessFork { child_comm, child_pid } => {
+                    comms.insert(*child_pid, child_comm.clone());
+                }
+                FtraceRecord::SchedProcessExit { comm, pid } => {
+                    comms.insert(*pid, comm.clone());
+                }
+                //task_newtask & task_rename are enabled by the funtrace runtime
+                FtraceRecord::Generic(_) => {
+                    let comm = if event.name == "task_rename" { event.field("newcomm") } else { event.field("comm") };
+                    if let (Some(pid), Some(comm)) = (event.field("pid").and_then(|pid| pid.parse::<u64>().ok()), comm) {
+                        comms.insert(pid, comm.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        //threads still switched out at the end of the ftrace data are assumed to stay off-CPU until then
+        for (tid, off) in switched_out {
+            add_switched_out(tid, off, end);
+        }
+        for (cpu, (tid, since)) in running {
+            cpu_runs.push(CpuRun { tid, cpu, start: since, end });
+        }
+        cpu_runs.retain(|run| run.tid != 0 && run.end > run.start);
+        cpu_runs.sort_by_key(|run| (run.cpu, run.start));
+        let mut thread_runs: HashMap<u64, Vec<CpuRun>> = HashMap::new();
+        for run in &cpu_runs {
+            thread_runs.entry(run.tid).or_default().push(*run);
+        }
+        for runs in thread_runs.values_mut() {
+            runs.sort_by_key(|run| run.start);
+        }
+        for thread_intervals in intervals.values_mut() {
+            thread_intervals.sort_by_key(|interval| interval.start);
+        }
+        OffCpuStats { intervals, wakeups, comms, cpu_runs, thread_runs, per_func: HashMap::new() }
+    }
+
+    pub fn thread_intervals(&self, tid: u64) -> &[OffCpuInterval] {
+        self.intervals.get(&tid).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    //the CPUs the thread ran on within [start, end), in the order it ran on them
+    //(more than one CPU means it migrated)
+    pub fn thread_cpus(&self, tid: u64, start: u64, end: u64) -> Vec<u32> {
+        let mut cpus: Vec<u32> = Vec::new();
+        if let Some(runs) = self.thread_runs.get(&tid) {
+            let first = runs.partition_point(|run| run.end <= start);
+            for run in runs[first..].iter().take_while(|run| run.start < end) {
+                if cpus.last() != Some(&run.cpu) {
+                    cpus.push(run.cpu);
+                }
+            }
+        }
+        cpus
+    }
+
+    //returns the (runnable, blocked) cycles of the thread within [start, end)
+    pub fn off_cpu_time(&self, tid: u64, start: u64, end: u64) -> (u64, u64) {
+        let (mut runnable, mut blocked) = (0, 0);
+        if let Some(thread_intervals) = self.intervals.get(&tid) {
+            let first = thread_intervals.partition_point(|interval| interval.end <= start);
+            for interval in &thread_intervals[first..] {
+                if interval.start >= end {
+                    break;
+                }
+                let overlap = interval.end.min(end) - interval.start.max(start);
+                match interval.state {
+                    OffCpuState::Runnable => runnable += overlap,
+                    OffCpuState::Blocked => blocked += overlap,
+                }
+            }
+        }
+        (runnable, blocked)
+    }
+
+    //the time of nested calls is attributed to all the functions on the stack, so a function's
+    //off-CPU time includes that of its callees (and a recursive function's time is counted
+    //once per active call)
+    pub fn add_call(&mut self, tid: u64, sym: &SymInfo, call_cycle: u64, return_cycle: u64) -> (u64, u64) {
+        let (runnable, blocked) = self.off_cpu_time(tid, call_cycle, return_cycle);
+        let times = self.per_func.entry(sym.clone()).or_default();
+        times.calls += 1;
+        times.wall += return_cycle - call_cycle;
+        times.runnable += runnable;
+        times.blocked += blocked;
+        (runnable, blocked)
+    }
+
+    //functions are listed in descending order of off-CPU time
+    pub fn write_report(&self, path: &str, cpu_freq: u64) -> io::Result<()> {
+        let mut report = File::create(path)?;
+        let rat = |n: u64| Ratio::from_u64(n).unwrap();
+        let us = |cycles: u64| rat2dec(&(rat(cycles) / (rat(cpu_freq) / rat(1000000))), 3);
+
+        let mut funcs: Vec<(&SymInfo, &FuncTimes)> = self.per_func.iter().collect();
+        funcs.sort_by_key(|(sym, times)| (std::cmp::Reverse(times.runnable + times.blocked), std::cmp::Reverse(times.wall), sym.demangled_func.clone()));
+
+        writeln!(report, "# times in microseconds; a function's time includes that of its callees")?;
+        writeln!(report, "# calls\twall\ton-CPU\trunnable\tblocked\tfunction")?;
+        for (sym, times) in funcs {
+            let on_cpu = times.wall - times.runnable - times.blocked;
+            writeln!(report, "{}\t{}\t{}\t{}\t{}\t{} ({}:{})", times.calls, us(times.wall), us(on_cpu), us(times.runnable), us(times.blocked), sym.demangled_func, sym.file, sym.line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::ftrace::parse_ftrace_lines;
+
+    //main (10) is preempted by worker (20) on CPU 0, resumes on CPU 1, blocks, and is woken up
+    //by worker; worker itself was forked & woken up before the first sched_switch
+    pub(crate) const FTRACE: &str = "
+          <idle>-0       [001] d.... 20: sched_wakeup_new: comm=worker pid=20 prio=120 target_cpu=000
+            main-10      [000] d.... 50: sched_switch: prev_comm=main prev_pid=10 prev_prio=120 prev_state=R+ ==> next_comm=worker next_pid=20 next_prio=120
+          <idle>-0       [001] d.... 100: sched_switch: prev_comm=swapper/1 prev_pid=0 prev_prio=120 prev_state=R ==> next_comm=main next_pid=10 next_prio=120
+            main-10      [001] d.... 150: sched_switch: prev_comm=main prev_pid=10 prev_prio=120 prev_state=D ==> next_comm=swapper/1 next_pid=0 next_prio=120
+          worker-20      [000] d.... 200: sched_waking: comm=main pid=10 prio=120 target_cpu=001
+          worker-20      [000] d.... 210: sched_wakeup: comm=main pid=10 prio=120 target_cpu=001
+          <idle>-0       [001] d.... 260: sched_switch: prev_comm=swapper/1 prev_pid=0 prev_prio=120 prev_state=R ==> next_comm=main next_pid=10 next_prio=120
+          worker-20      [000] d.... 300: sched_switch: prev_comm=worker prev_pid=20 prev_prio=120 prev_state=S ==> next_comm=swapper/0 next_pid=0 next_prio=120
+";
+
+    fn sym(name: &str) -> SymInfo {
+        SymInfo { func: name.to_string(), demangled_func: name.to_string(), file: "a.cpp".to_string(), line: 1, executable_file: "a.out".to_string(), static_addr: 0, size: 0 }
+    }
+
+    #[test]
+    fn off_cpu_intervals() {
+        let stats = OffCpuStats::new(&parse_ftrace_lines(FTRACE));
+        let intervals: Vec<(u64, u64, OffCpuState, Option<u64>)> = stats.thread_intervals(10).iter().map(|i| (i.start, i.end, i.state, i.waker)).collect();
+        assert_eq!(intervals, [
+            (50, 100, OffCpuState::Runnable, None),
+            (150, 200, OffCpuState::Blocked, Some(20)),
+            (200, 260, OffCpuState::Runnable, Some(20)),
+        ]);
+        //switched out by the last event, so there's no time off-CPU to speak of
+        assert!(stats.thread_intervals(20).is_empty());
+
+        let wakeups: Vec<(u64, u64, u64, Option<u64>)> = stats.wakeups.iter().map(|w| (w.timestamp, w.waker, w.woken, w.resumed)).collect();
+        assert_eq!(wakeups, [(20, 0, 20, Some(50)), (200, 20, 10, Some(260))]);
+        assert_eq!(stats.comms[&20], "worker");
+    }
+
+    #[test]
+    fn cpu_runs() {
+        let stats = OffCpuStats::new(&parse_ftrace_lines(FTRACE));
+        let runs: Vec<(u32, u64, u64, u64)> = stats.cpu_runs.iter().map(|r| (r.cpu, r.tid, r.start, r.end)).collect();
+        //main's first run on CPU 0 started before the first sched_switch there, so it's said to
+        //start with the ftrace data; the runs of the idle task are left out
+        assert_eq!(runs, [(0, 10, 20, 50), (0, 20, 50, 300), (1, 10, 100, 150), (1, 10, 260, 300)]);
+        assert_eq!(stats.thread_cpus(10, 0, 300), [0, 1]);
+        assert_eq!(stats.thread_cpus(10, 100, 300), [1]);
+        assert_eq!(stats.thread_cpus(20, 0, 50), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn per_function_split() {
+        let mut stats = OffCpuStats::new(&parse_ftrace_lines(FTRACE));
+        assert_eq!(stats.add_call(10, &sym("outer"), 40, 280), (50 + 60, 50));
+        assert_eq!(stats.add_call(10, &sym("inner"), 120, 220), (20, 50));
+        assert_eq!(stats.add_call(10, &sym("inner"), 100, 150), (0, 0));
+        let inner = &stats.per_func[&sym("inner")];
+        assert_eq!((inner.calls, inner.wall, inner.runnable, inner.blocked), (2, 150, 20, 50));
+
+        let path = std::env::temp_dir().join(format!("offcpu-test-{}.txt", std::process::id()));
+        stats.write_report(path.to_str().unwrap(), 1000000).unwrap();
+        let report = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = report.lines().skip(2).collect();
+        assert_eq!(lines, [
+            "1\t240.000\t80.000\t110.000\t50.000\touter (a.cpp:1)",
+            "2\t150.000\t80.000\t20.000\t50.000\tinner (a.cpp:1)",
+        ]);
+    }
+}
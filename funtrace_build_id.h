@@ -0,0 +1,29 @@
+#pragma once
+
+//shared by funtrace.cpp & funcount.cpp, which define NOINSTR before including this
+#include <cstdint>
+#include <cstring>
+#include <ostream>
+#include <iomanip>
+#include <link.h>
+
+//prints the build ID from the GNU build ID note in a PT_NOTE segment, if it has one (the notes
+//are loaded along with the code, so we needn't open the file)
+static void NOINSTR print_build_id(std::ostream& s, const char* notes, uint64_t size, const char* name)
+{
+    auto align4 = [](uint64_t n) { return (n + 3) & ~3ULL; };
+    const char* p = notes;
+    while(p + sizeof(ElfW(Nhdr)) <= notes + size) {
+        const ElfW(Nhdr)& note = *(const ElfW(Nhdr)*)p;
+        const char* note_name = p + sizeof note;
+        const unsigned char* desc = (const unsigned char*)note_name + align4(note.n_namesz);
+        if(note.n_type == NT_GNU_BUILD_ID && note.n_namesz == 4 && memcmp(note_name, "GNU", 4) == 0) {
+            for(uint32_t i=0; i<note.n_descsz; ++i) {
+                s << std::setw(2) << std::setfill('0') << (int)desc[i];
+            }
+            s << ' ' << name << '\n';
+            return;
+        }
+        p = (const char*)desc + align4(note.n_descsz);
+    }
+}
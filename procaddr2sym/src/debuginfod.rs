@@ -0,0 +1,205 @@
+//a minimal client for the debuginfod HTTP protocol (https://sourceware.org/elfutils/Debuginfod.html),
+//fetching debug info & source files by build ID from the servers listed in $DEBUGINFOD_URLS, with
+//an on-disk cache keyed by build ID. only http:// URLs are supported (no TLS) - the use case is
+//an artifact store or a local debuginfod stand-in rather than public servers
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_REDIRECTS: usize = 4;
+
+pub struct Debuginfod {
+    pub urls: Vec<String>,
+    //files are kept in cache_dir/build-id/debuginfo and cache_dir/build-id/source##path##to##file
+    //(the layout of elfutils' debuginfod client cache)
+    pub cache_dir: PathBuf,
+    failed: HashSet<PathBuf>, //files no server had, so as not to ask again
+    //https:// etc. servers in $DEBUGINFOD_URLS, which we can't talk to; reported once, when a file is missing
+    unsupported_urls: Vec<String>,
+}
+
+impl Debuginfod {
+    //the servers are taken from $DEBUGINFOD_URLS (space-separated) and the cache directory from
+    //$DEBUGINFOD_CACHE_PATH, or $XDG_CACHE_HOME/funtrace-debuginfod, or ~/.cache/funtrace-debuginfod
+    pub fn from_env() -> Self {
+        //$DEBUGINFOD_URLS often lists public https:// servers, set system-wide by the distro; we drop them
+        //here rather than failing to fetch every file from them
+        let (urls, unsupported_urls) = std::env::var("DEBUGINFOD_URLS").unwrap_or_default().split_whitespace()
+            .map(|url| url.to_string()).partition(|url| url.starts_with("http://"));
+        let cache_dir = match (std::env::var("DEBUGINFOD_CACHE_PATH"), std::env::var("XDG_CACHE_HOME"), std::env::var("HOME")) {
+            (Ok(cache_path), _, _) => PathBuf::from(cache_path),
+            (_, Ok(cache_home), _) => PathBuf::from(cache_home).join("funtrace-debuginfod"),
+            (_, _, Ok(home)) => PathBuf::from(home).join(".cache/funtrace-debuginfod"),
+            _ => PathBuf::from(".funtrace-debuginfod"),
+        };
+        Debuginfod { urls, cache_dir, failed: HashSet::new(), unsupported_urls }
+    }
+
+    //the local path of the debug info file with this build ID, downloaded unless it's already in the cache
+    pub fn debuginfo(&mut self, build_id: &str) -> Option<PathBuf> {
+        self.fetch(build_id, "debuginfo", "debuginfo")
+    }
+
+    //the local path of the executable file with this build ID, downloaded unless it's already in the cache
+    pub fn executable(&mut self, build_id: &str) -> Option<PathBuf> {
+        self.fetch(build_id, "executable", "executable")
+    }
+
+    //the local path of a source file (the absolute path from the debug info) of the binary with this build ID
+    pub fn source(&mut self, build_id: &str, path: &str) -> Option<PathBuf> {
+        if !path.starts_with('/') {
+            return None;
+        }
+        self.fetch(build_id, &format!("source{}", path.replace('/', "#")), &format!("source{}", url_escape(path)))
+    }
+
+    fn fetch(&mut self, build_id: &str, cache_name: &str, url_suffix: &str) -> Option<PathBuf> {
+        let cached = self.cache_dir.join(build_id).join(cache_name);
+        if cached.exists() {
+            return Some(cached);
+        }
+        if !self.unsupported_urls.is_empty() {
+            println!("WARNING: ignoring the debuginfod servers {} in $DEBUGINFOD_URLS - only http:// servers are supported", self.unsupported_urls.join(" "));
+            self.unsupported_urls.clear();
+        }
+        if self.urls.is_empty() || self.failed.contains(&cached) {
+            return None;
+        }
+        //downloaded under a temporary name first so that an interrupted download isn't taken for a cached file
+        //(the suffix is appended rather than replacing the extension, since source##a##x.c and source##a##x.h
+        //would get the same temporary name otherwise)
+        let mut tmp = cached.clone().into_os_string();
+        tmp.push(".part");
+        let tmp = PathBuf::from(tmp);
+        for url in &self.urls {
+            let url = format!("{}/buildid/{}/{}", url.trim_end_matches('/'), build_id, url_suffix);
+            let file = match fs::create_dir_all(cached.parent().unwrap()).and_then(|_| File::create(&tmp)) {
+                Ok(file) => file,
+                Err(e) => {
+                    println!("WARNING: failed to create {} in the debuginfod cache - {}", tmp.display(), e);
+                    break;
+                }
+            };
+            let mut out = BufWriter::new(file);
+            match http_get(&url, &mut out).and_then(|found| out.flush().map(|_| found)) {
+                Ok(true) => match fs::rename(&tmp, &cached) {
+                    Ok(_) => return Some(cached),
+                    Err(e) => println!("WARNING: failed to save {} into the debuginfod cache at {} - {}", url, cached.display(), e),
+                }
+                Ok(false) => {}
+                Err(e) => println!("WARNING: debuginfod request {} failed - {}", url, e),
+            }
+        }
+        let _ = fs::remove_file(&tmp);
+        self.failed.insert(cached);
+        None
+    }
+}
+
+//percent-encodes the characters that can't appear in a URL path as is
+fn url_escape(path: &str) -> String {
+    let mut escaped = String::new();
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            escaped.push(b as char);
+        }
+        else {
+            escaped.push_str(&format!("%{:02X}", b));
+        }
+    }
+    escaped
+}
+
+fn http_error(message: String) -> io::Error {
+    io::Error::other(message)
+}
+
+//copies the body of a GET request to out (streaming, since debug info files can take gigabytes), returning false
+//if the server doesn't have the file (404); follows redirects
+fn http_get(url: &str, out: &mut impl Write) -> io::Result<bool> {
+    let mut url = url.to_string();
+    for _ in 0..MAX_REDIRECTS {
+        let rest = url.strip_prefix("http://").ok_or_else(|| http_error(format!("only http:// URLs are supported, got {}", url)))?;
+        let (host, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        let host_port = if host.rsplit(']').next().unwrap_or(host).contains(':') { host.to_string() } else { format!("{}:80", host) };
+        let addr = host_port.to_socket_addrs()?.next().ok_or_else(|| http_error(format!("couldn't resolve {}", host)))?;
+        let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: funtrace\r\nConnection: close\r\n\r\n", path, host).as_bytes())?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line)?;
+        let status: u32 = status_line.split_whitespace().nth(1).and_then(|status| status.parse().ok())
+            .ok_or_else(|| http_error(format!("bad HTTP status line `{}'", status_line.trim())))?;
+        let (mut content_length, mut chunked, mut location) = (None, false, None);
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                let value = value.trim();
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "content-length" => content_length = value.parse::<u64>().ok(),
+                    "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+                    "location" => location = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        if (300..400).contains(&status) {
+            if let Some(location) = location {
+                url = if location.starts_with('/') { format!("http://{}{}", host, location) } else { location };
+                continue;
+            }
+        }
+        if status == 404 {
+            return Ok(false);
+        }
+        if status != 200 {
+            return Err(http_error(format!("HTTP status `{}'", status_line.trim())));
+        }
+
+        if chunked {
+            loop {
+                let mut size_line = String::new();
+                reader.read_line(&mut size_line)?;
+                let size_str = size_line.trim().split(';').next().unwrap_or("");
+                let size = u64::from_str_radix(size_str, 16).map_err(|_| http_error(format!("bad chunk size `{}'", size_line.trim())))?;
+                if size == 0 {
+                    break;
+                }
+                copy_exactly(&mut reader, size, out)?;
+                let mut crlf = String::new();
+                reader.read_line(&mut crlf)?;
+            }
+        }
+        else if let Some(length) = content_length {
+            copy_exactly(&mut reader, length, out)?;
+        }
+        else {
+            io::copy(&mut reader, out)?;
+        }
+        return Ok(true);
+    }
+    Err(http_error(format!("too many redirects fetching {}", url)))
+}
+
+//copies length bytes from the reader, failing if it ends before that
+fn copy_exactly(reader: &mut impl Read, length: u64, out: &mut impl Write) -> io::Result<()> {
+    let copied = io::copy(&mut reader.take(length), out)?;
+    if copied < length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("the connection was closed after {} of {} bytes", copied, length)));
+    }
+    Ok(())
+}
@@ -1,24 +1,37 @@
 use addr2line::{
     Context,
     object,
-    gimli::{EndianReader, RunTimeEndian},
+    gimli::{self, EndianReader, Reader, RunTimeEndian},
 };
 use addr2line::fallible_iterator::FallibleIterator;
 use std::rc::Rc;
 use procfs::process::{MemoryMaps, MemoryMap, MMapPath};
 use procfs::FromBufRead;
 use goblin::elf::{Elf, ProgramHeader};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::time::SystemTime;
 use std::fs::File;
-use std::io::Read;
 use std::fs;
+use std::mem;
+use std::cell::OnceCell;
+use std::path::PathBuf;
 use chrono::{DateTime, Local};
 use memmap2::Mmap;
-use serde_json::Value;
-use cpp_demangle;
+use iced_x86::{Decoder, DecoderOptions, FlowControl, Mnemonic};
 
-fn find_address_in_maps(address: u64, maps: &Vec<MemoryMap>) -> Option<&MemoryMap> {
+pub mod debuginfod;
+use debuginfod::Debuginfod;
+mod split_dwarf;
+use split_dwarf::SplitDwarf;
+mod symbol_index;
+use symbol_index::IndexedFunction;
+mod symtable;
+use symtable::SymTable;
+mod substitute_path;
+use substitute_path::{PathKind, SubsRule, parse_substitute_path_json, substitute_path};
+pub mod trace_file;
+
+fn find_address_in_maps(address: u64, maps: &[MemoryMap]) -> Option<&MemoryMap> {
     maps.binary_search_by(|map| {
         if address < map.address.0 {
             std::cmp::Ordering::Greater // Address is before this map
@@ -72,7 +85,7 @@ fn read_elf_symbols(elf: &Elf) -> Vec<Symbol> {
     symbols
 }
 
-fn find_symbol(symbols: &Vec<Symbol>, address: u64) -> Option<&Symbol> {
+fn find_symbol(symbols: &[Symbol], address: u64) -> Option<&Symbol> {
     // Binary search for the largest base address that's <= our target address
     let idx = match symbols.binary_search_by_key(&address, |sym| sym.base_address) {
         Ok(exact) => exact,
@@ -93,67 +106,356 @@ fn find_symbol(symbols: &Vec<Symbol>, address: u64) -> Option<&Symbol> {
     }
 }
 
-#[derive(Debug)]
-struct SubsPath {
-    src: String,
-    dst: String,
+//an entry in the xray_instr_map section describing an instrumentation point ("sled")
+struct XRaySled {
+    address: u64,
+    function: u64, //the entry address of the function containing the sled
+    kind: u8,
 }
 
-fn parse_substitute_path_json(file_name: &str) -> Vec<SubsPath> {
-    let mut file = match File::open(file_name) {
-        Ok(file) => file,
-        Err(_) => {
-            return Vec::new();
+const XRAY_SLED_ENTRY_SIZE: usize = 32;
+//sled kinds calling __xray_FunctionEntry (the others jump to the exit handlers)
+const XRAY_SLED_KIND_ENTRY: u8 = 0;
+const XRAY_SLED_KIND_LOG_ARGS_ENTER: u8 = 3;
+//a patched entry sled is `mov $function_id, %r10d; call __xray_FunctionEntry`, so the address
+//logged by the entry handler (the return address of the call) is this far from the sled
+const XRAY_ENTRY_SLED_SIZE: u64 = 11;
+
+//the sleds in the xray_instr_map section, sorted by address, and the XRay function IDs of the functions
+//containing them (function entry address -> ID; IDs are assigned by the XRay runtime the same way - starting
+//from 1, in the order of the functions' sleds in the section)
+fn read_xray_instr_map(elf: &Elf, data: &[u8]) -> (Vec<XRaySled>, HashMap<u64, u32>) {
+    let mut sleds = Vec::new();
+    let mut function_ids = HashMap::new();
+    let Some(sh) = elf.section_headers.iter().find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some("xray_instr_map")) else { return (sleds, function_ids) };
+    let start = (sh.sh_offset as usize).min(data.len());
+    let map = &data[start..(start + sh.sh_size as usize).min(data.len())];
+    //before version 2, the addresses are absolute, so in position-independent binaries they're filled by relocations
+    let relocs: HashMap<u64, i64> = elf.dynrelas.iter().filter_map(|rel| rel.r_addend.map(|addend| (rel.r_offset, addend))).collect();
+    let word = |entry: &[u8], offset: usize| u64::from_le_bytes(entry[offset..offset+8].try_into().unwrap());
+    let mut prev_function = None;
+    let mut num_functions = 0;
+    for (i, entry) in map.chunks_exact(XRAY_SLED_ENTRY_SIZE).enumerate() {
+        let entry_addr = sh.sh_addr + (i * XRAY_SLED_ENTRY_SIZE) as u64;
+        let (kind, version) = (entry[16], entry[18]);
+        let (address, function) = if version >= 2 {
+            //relative to the address of the field holding them
+            (entry_addr.wrapping_add(word(entry, 0)), (entry_addr + 8).wrapping_add(word(entry, 8)))
         }
-    };
+        else {
+            let absolute = |offset: usize| match word(entry, offset) {
+                0 => relocs.get(&(entry_addr + offset as u64)).map(|&addend| addend as u64).unwrap_or(0),
+                addr => addr,
+            };
+            (absolute(0), absolute(8))
+        };
+        if prev_function != Some(function) {
+            num_functions += 1;
+            function_ids.entry(function).or_insert(num_functions);
+            prev_function = Some(function);
+        }
+        sleds.push(XRaySled { address, function, kind });
+    }
+    sleds.sort_by_key(|sled| sled.address);
+    (sleds, function_ids)
+}
+
+//the entry address of the function whose XRay entry sled logged the given address
+fn find_xray_function(sleds: &[XRaySled], address: u64) -> Option<u64> {
+    let sled_address = address.checked_sub(XRAY_ENTRY_SLED_SIZE)?;
+    let index = sleds.binary_search_by_key(&sled_address, |sled| sled.address).ok()?;
+    let sled = &sleds[index];
+    matches!(sled.kind, XRAY_SLED_KIND_ENTRY | XRAY_SLED_KIND_LOG_ARGS_ENTER).then_some(sled.function)
+}
+
+//the symbol starting exactly at the given address; of several aliases, the first sized one
+fn find_function_symbol(symbols: &[Symbol], address: u64) -> Option<&Symbol> {
+    let first = symbols.partition_point(|sym| sym.base_address < address);
+    let aliases = &symbols[first..first + symbols[first..].partition_point(|sym| sym.base_address == address)];
+    aliases.iter().find(|sym| sym.size > 0).or(aliases.first())
+}
+
+//maps a path from the proc maps or the debug info to a local file: applies substitute-path.json, and then looks
+//for the file under each sysroot in order, leaving the path as is if it's not found under any of them.
+//a free function rather than only a method to be usable while other ProcAddr2Sym fields are borrowed
+fn local_path(subs_path: &[SubsRule], sysroots: &[String], kind: PathKind, path: String) -> String {
+    let path = substitute_path(subs_path, kind, path);
+    if path.starts_with('/') {
+        for sysroot in sysroots {
+            let rooted = format!("{}{}", sysroot.trim_end_matches('/'), path);
+            if fs::metadata(&rooted).is_ok() {
+                return rooted;
+            }
+        }
+    }
+    path
+}
 
-    let mut json_str = String::new();
-    if let Err(e) = file.read_to_string(&mut json_str) {
-        eprintln!("Warning: Failed to read from file '{}': {}", file_name, e);
+//$FUNTRACE_SUBSTITUTE_PATH, or substitute-path.json in the working directory if it exists
+fn default_substitute_path() -> Vec<SubsRule> {
+    let (file_name, required) = match std::env::var("FUNTRACE_SUBSTITUTE_PATH") {
+        Ok(file_name) => (file_name, true),
+        Err(_) => ("substitute-path.json".to_string(), false),
+    };
+    if !required && fs::metadata(&file_name).is_err() {
         return Vec::new();
     }
+    parse_substitute_path_json(&file_name).unwrap_or_else(|e| {
+        println!("ERROR: {} - not remapping any paths", e);
+        Vec::new()
+    })
+}
+
+//the CRC-32 used by .gnu_debuglink (the zlib one)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+//.zdebug_info is the legacy (pre-SHF_COMPRESSED) GNU name of a compressed .debug_info; both kinds of compressed
+//sections are decompressed by object when addr2line loads them
+fn has_debug_info(elf: &Elf) -> bool {
+    elf.section_headers.iter().any(|sh| sh.sh_type != goblin::elf::section_header::SHT_NOBITS && sh.sh_size > 0
+        && matches!(elf.shdr_strtab.get_at(sh.sh_name), Some(".debug_info") | Some(".zdebug_info")))
+}
+
+fn build_id(elf: &Elf, data: &[u8]) -> Option<String> {
+    let note = elf.iter_note_sections(data, Some(".note.gnu.build-id"))?
+        .filter_map(|note| note.ok())
+        .find(|note| note.n_type == goblin::elf::note::NT_GNU_BUILD_ID)?;
+    Some(note.desc.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+//the file name and CRC in the .gnu_debuglink section
+fn debuglink(elf: &Elf, data: &[u8]) -> Option<(String, u32)> {
+    let sh = elf.section_headers.iter().find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(".gnu_debuglink"))?;
+    let section = data.get(sh.sh_offset as usize..(sh.sh_offset + sh.sh_size) as usize)?;
+    let name_len = section.iter().position(|&b| b == 0)?;
+    let crc_offset = (name_len + 4) & !3; //the name is padded to 4 bytes
+    let crc_bytes: [u8; 4] = section.get(crc_offset..crc_offset+4)?.try_into().ok()?;
+    let crc = if elf.little_endian { u32::from_le_bytes(crc_bytes) } else { u32::from_be_bytes(crc_bytes) };
+    Some((String::from_utf8_lossy(&section[..name_len]).to_string(), crc))
+}
+
+type DwarfReader = EndianReader<RunTimeEndian, Rc<[u8]>>;
 
-    let json_value: Value = match serde_json::from_str(&json_str) {
-        Ok(value) => value,
-        Err(e) => {
-            eprintln!("Warning: Failed to parse JSON in file '{}': {}", file_name, e);
-            return Vec::new();
+//appends a path component the way addr2line does (an absolute path replaces what we had)
+fn path_push(path: &mut String, p: &str) {
+    if p.starts_with('/') {
+        *path = p.to_string();
+    }
+    else {
+        if !path.is_empty() && !path.ends_with('/') {
+            path.push('/');
         }
+        path.push_str(p);
+    }
+}
+
+//the path of a DW_AT_decl_file index, rendered like addr2line renders the file names it returns
+fn dwarf_file_name(dwarf: &gimli::Dwarf<DwarfReader>, unit: &gimli::Unit<DwarfReader>, index: u64) -> Option<String> {
+    let header = unit.line_program.as_ref()?.header();
+    let file = header.file(index)?;
+    let mut path = match unit.comp_dir {
+        Some(ref comp_dir) => comp_dir.to_string_lossy().ok()?.into_owned(),
+        None => String::new(),
     };
+    if file.directory_index() != 0 {
+        if let Some(directory) = file.directory(header) {
+            path_push(&mut path, &dwarf.attr_string(unit, directory).ok()?.to_string_lossy().ok()?);
+        }
+    }
+    path_push(&mut path, &dwarf.attr_string(unit, file.path_name()).ok()?.to_string_lossy().ok()?);
+    Some(path)
+}
 
-    let mut subs_paths = Vec::new();
-
-    if let Some(array) = json_value.as_array() {
-        for item in array {
-            if let Some(inner_array) = item.as_array() {
-                if inner_array.len() == 2 {
-                    if let (Some(src), Some(dst)) = (inner_array[0].as_str(), inner_array[1].as_str()) {
-                        subs_paths.push(SubsPath {
-                            src: src.to_string(),
-                            dst: dst.to_string(),
-                        });
-                    } else {
-                        eprintln!("Warning: Invalid string pair in file '{}'", file_name);
+//the DW_AT_decl_file & DW_AT_decl_line of the functions defined in a compilation unit, by their entry addresses.
+//an out-of-line instance of an inline function or the definition of a member function might not have these
+//attributes, in which case we take them from the DIE it refers to. the file indexes are looked up in the line
+//table of line_unit - the unit itself, or the skeleton unit in the executable file for a split DWARF unit
+type DwarfUnit<'a> = (&'a gimli::Dwarf<DwarfReader>, &'a gimli::Unit<DwarfReader>);
+fn read_function_decls((dwarf, unit): DwarfUnit, (line_dwarf, line_unit): DwarfUnit, decls: &mut HashMap<u64, (String, u32)>) -> gimli::Result<()> {
+    let mut entries = unit.entries();
+    while let Some((_, entry)) = entries.next_dfs()? {
+        if entry.tag() != gimli::DW_TAG_subprogram {
+            continue;
+        }
+        //a function split into parts (such as gcc's "[clone .cold]" code) has DW_AT_ranges rather than DW_AT_low_pc,
+        //and each part's symbol starts a range
+        let entry_addresses = match entry.attr_value(gimli::DW_AT_low_pc)? {
+            Some(gimli::AttributeValue::Addr(addr)) => vec![addr],
+            Some(gimli::AttributeValue::DebugAddrIndex(index)) => vec![dwarf.address(unit, index)?],
+            _ => {
+                let mut begins = Vec::new();
+                if let Ok(mut ranges) = dwarf.die_ranges(unit, entry) {
+                    while let Ok(Some(range)) = ranges.next() {
+                        begins.push(range.begin);
                     }
-                } else {
-                    eprintln!("Warning: Array does not contain exactly 2 elements in file '{}'", file_name);
                 }
-            } else {
-                eprintln!("Warning: Expected array in file '{}'", file_name);
+                begins
+            }
+        };
+        if entry_addresses.is_empty() {
+            continue;
+        }
+        let mut die = entry.clone();
+        for _ in 0..4 { //the reference chain is normally 1-2 DIEs long
+            if let (Some(file), Some(line)) = (die.attr_value(gimli::DW_AT_decl_file)?, die.attr_value(gimli::DW_AT_decl_line)?.and_then(|line| line.udata_value())) {
+                let index = match file {
+                    gimli::AttributeValue::FileIndex(index) => Some(index),
+                    file => file.udata_value(),
+                };
+                if let Some(file) = index.and_then(|index| dwarf_file_name(line_dwarf, line_unit, index)) {
+                    for &address in &entry_addresses {
+                        decls.insert(address, (file.clone(), line as u32));
+                    }
+                }
+                break;
+            }
+            let origin = match die.attr_value(gimli::DW_AT_abstract_origin)? {
+                Some(origin) => Some(origin),
+                None => die.attr_value(gimli::DW_AT_specification)?,
+            };
+            match origin {
+                Some(gimli::AttributeValue::UnitRef(offset)) => die = unit.entry(offset)?,
+                _ => break,
             }
         }
-    } else {
-        eprintln!("Warning: Top level object is not an array in file '{}'", file_name);
     }
-
-    subs_paths
+    Ok(())
 }
 
 struct ExecutableFileMetadata
 {
+    path: String,
+    data_file: String, //the file data was read from - path, or a file with the right build ID found elsewhere
     program_headers: Vec<ProgramHeader>,
-    addr2line: Context<EndianReader<RunTimeEndian, Rc<[u8]>>>,
+    addr2line: OnceCell<Context<DwarfReader>>, //created on first use (see addr2line())
+    dwarf_file: Option<String>, //the separate debug info file with the DWARF, if it's not this file itself
     symbols: Vec<Symbol>,
+    data: Mmap, //kept for disassembling functions
+    xray_sleds: Vec<XRaySled>,
+    xray_function_ids: HashMap<u64, u32>,
+    build_id: Option<String>, //for fetching the source files from debuginfod
+    //the functions looked up so far (or in previous runs, if we have a symbol index for this file), by address
+    functions: HashMap<u64, IndexedFunction>,
+    index_path: Option<PathBuf>,
+    index_dirty: bool, //if functions or symbols aren't yet saved in the index
+}
+
+impl ExecutableFileMetadata {
+    //the DWARF is only parsed when we need something not kept in the symbol index
+    fn addr2line(&self) -> &Context<DwarfReader> {
+        self.addr2line.get_or_init(|| {
+            let debug_data = self.dwarf_file.as_ref().and_then(|dwarf_file| match File::open(dwarf_file).and_then(|file| unsafe { Mmap::map(&file) }) {
+                Ok(debug_data) => Some(debug_data),
+                Err(e) => {
+                    println!("WARNING: couldn't open debug info file {} of {} - {}", dwarf_file, self.path, e);
+                    None
+                }
+            });
+            //if the debug info can't be read, we still have the ELF symbols (and ??:0 for file:line)
+            let dwarf_data = debug_data.as_deref().unwrap_or(&self.data);
+            match object::File::parse(dwarf_data).map_err(|e| e.to_string()).and_then(|object| addr2line::Context::new(&object).map_err(|e| e.to_string())) {
+                Ok(ctx) => ctx,
+                Err(e) => {
+                    println!("WARNING: failed to read the debug info of {} - {}", self.path, e);
+                    let empty = EndianReader::new(Rc::from(&[][..]), RunTimeEndian::default());
+                    addr2line::Context::from_dwarf(gimli::Dwarf::load(|_| Ok::<_, gimli::Error>(empty.clone())).unwrap()).unwrap()
+                }
+            }
+        })
+    }
+}
+
+//the compiler instrumentation methods supported by funtrace, as detected in an executable file
+//by ProcAddr2Sym::instrumentation()
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Instrumentation {
+    FinstrumentFunctions, //-finstrument-functions: calls to __cyg_profile_func_enter/exit
+    Pg, //-pg -mfentry -minstrument-return=call: calls to __fentry__/__return__
+    XRay, //-fxray-instrument: an xray_instr_map section
+}
+
+impl Instrumentation {
+    //whether a function tail-calling another logs its return before jumping to the callee, which makes
+    //the callee look like it was called by the tail-caller's caller (-finstrument-functions disables
+    //tail calls in instrumented functions since the exit hook must be called after the callee returns)
+    pub fn has_tail_call_artifacts(self) -> bool {
+        self != Instrumentation::FinstrumentFunctions
+    }
+
+    //the inverse of to_string()
+    pub fn from_name(name: &str) -> Option<Instrumentation> {
+        [Instrumentation::FinstrumentFunctions, Instrumentation::Pg, Instrumentation::XRay].into_iter().find(|method| method.to_string() == name)
+    }
+}
+
+impl std::fmt::Display for Instrumentation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Instrumentation::FinstrumentFunctions => "-finstrument-functions",
+            Instrumentation::Pg => "-pg",
+            Instrumentation::XRay => "XRay",
+        })
+    }
+}
+
+const INSTRUMENTATION_HOOKS: [(&str, Instrumentation); 4] = [
+    ("__cyg_profile_func_enter", Instrumentation::FinstrumentFunctions),
+    ("__cyg_profile_func_exit", Instrumentation::FinstrumentFunctions),
+    ("__fentry__", Instrumentation::Pg),
+    ("__return__", Instrumentation::Pg),
+];
+
+//the instrumentation methods used in an ELF file. since the funtrace runtime defines the hooks
+//of all the methods, the presence of a hook's symbol doesn't mean much in an executable linked
+//with the runtime; we look for functions calling a hook's address before calling anything else
+//(as the entry hooks are called) instead. shared objects don't define the hooks, so there, an
+//undefined symbol is a sign of the hook being called (through the PLT)
+fn detect_instrumentation(elf: &Elf, data: &[u8]) -> Vec<Instrumentation> {
+    let mut methods = Vec::new();
+    if elf.section_headers.iter().any(|sh| sh.sh_size > 0 && elf.shdr_strtab.get_at(sh.sh_name) == Some("xray_instr_map")) {
+        methods.push(Instrumentation::XRay);
+    }
+    let mut hooks: HashMap<u64, Instrumentation> = HashMap::new();
+    for (syms, strtab) in [(&elf.dynsyms, &elf.dynstrtab), (&elf.syms, &elf.strtab)] {
+        for sym in syms.iter() {
+            let Some(&(_, method)) = strtab.get_at(sym.st_name).and_then(|name| INSTRUMENTATION_HOOKS.iter().find(|(hook, _)| *hook == name)) else { continue };
+            if sym.is_import() {
+                methods.push(method);
+            }
+            else if sym.st_value != 0 {
+                hooks.insert(sym.st_value, method);
+            }
+        }
+    }
+    if !hooks.is_empty() {
+        //disassemble the start of every function up to its first call; scanning all of the code for call
+        //instructions would also find the runtime calling its own hooks, and bytes which only look like calls
+        let code_sections: Vec<_> = elf.section_headers.iter().filter(|sh| sh.is_executable() && sh.sh_type == goblin::elf::section_header::SHT_PROGBITS).collect();
+        for sym in elf.syms.iter().chain(elf.dynsyms.iter()).filter(|sym| sym.is_function() && sym.st_value != 0 && sym.st_size != 0) {
+            let Some(sh) = code_sections.iter().find(|sh| sym.st_value >= sh.sh_addr && sym.st_value < sh.sh_addr + sh.sh_size) else { continue };
+            let start = ((sym.st_value - sh.sh_addr + sh.sh_offset) as usize).min(data.len());
+            let code = &data[start..(start + sym.st_size as usize).min(data.len())];
+            //-finstrument-functions saves a few registers & loads the hook's arguments before the call
+            const MAX_INSTRUCTIONS_BEFORE_HOOK: usize = 16;
+            let first_call = Decoder::with_ip(64, code, sym.st_value, DecoderOptions::NONE).into_iter()
+                .take(MAX_INSTRUCTIONS_BEFORE_HOOK).find(|instruction| instruction.flow_control() == FlowControl::Call);
+            if let Some(&method) = first_call.and_then(|call| hooks.get(&call.near_branch_target())) {
+                methods.push(method);
+            }
+        }
+    }
+    methods.sort();
+    methods.dedup();
+    methods
 }
 
 pub struct InputSource {
@@ -171,8 +473,35 @@ pub struct ProcAddr2Sym {
     sym_missing: HashSet<String>,
     offset_cache: HashMap<u64, u64>,
     source_files: HashSet<String>, //kept just to print "modified after the input source" warnings once per file
-    subs_path: Vec<SubsPath>,
+    subs_path: Vec<SubsRule>,
     pub input_source: Option<InputSource>,
+    //when false, only the function name & static address are looked up, and file:line
+    //are left as ??:0 - much faster for callers only interested in function names
+    pub find_source_lines: bool,
+    instrumentation_cache: HashMap<String, Vec<Instrumentation>>,
+    //executable file -> function entry address -> (decl file, decl line), filled per compilation unit (read_units)
+    function_decls: HashMap<String, HashMap<u64, (String, u32)>>,
+    read_units: HashSet<(String, Option<gimli::DwoId>, gimli::UnitSectionOffset)>, //.dwo units all start at offset 0, hence the DwoId
+    split_dwarf: HashMap<String, SplitDwarf>, //the .dwo/.dwp files loaded for an executable file built with -gsplit-dwarf
+    //where to look for the separate debug info files of stripped executable files (see find_debug_file());
+    //initialized from $FUNTRACE_DEBUG_DIRS (colon-separated) or to /usr/lib/debug
+    pub debug_dirs: Vec<String>,
+    //the root directories the paths in the proc maps & debug info are relative to, tried in order (like gdb's
+    //sysroot, for processes running in containers or chroots); initialized from $FUNTRACE_SYSROOT (colon-separated)
+    pub sysroots: Vec<String>,
+    //for fetching debug info & source files missing locally
+    pub debuginfod: Debuginfod,
+    //the build IDs of the executable files at the time the trace was taken, by their path in the proc maps
+    build_ids: HashMap<String, String>,
+    //where the symbol indexes of the executable files are kept (see symbol_index.rs), or None to not use them.
+    //initialized from $FUNTRACE_SYMBOL_INDEX_DIR (set it to an empty string to disable the indexes), or to
+    //$XDG_CACHE_HOME/funtrace-symbols or ~/.cache/funtrace-symbols
+    pub symbol_index_dir: Option<PathBuf>,
+    //whether to print the symbol index used for each executable file (false by default since funcount2sym
+    //prints its report to stdout)
+    pub report_symbol_index_hits: bool,
+    //the symbols resolved where the trace was taken (see set_symtable()), used instead of the binaries
+    symtable: SymTable,
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, std::cmp::Eq)]
@@ -180,9 +509,8 @@ pub struct SymInfo {
     pub func: String, //before c++filt
     pub demangled_func: String, //after c++filt
     //note that these are, whenever possible, the file:line of the FIRST function
-    //address, NOT the address passed to proc_addr2sym!
-    //TODO: given demand we can provide a way to pass the file:line of the actual
-    //address passed to proc_addr2sym
+    //address, NOT the address passed to proc_addr2sym! use proc_addr2line for the
+    //file:line of the actual address
     pub file: String, //source file
     pub line: u32, //line number in the file
     pub executable_file: String, //executable or shared object
@@ -194,6 +522,42 @@ pub struct SymInfo {
     pub size: u64, //0 if no symbol found
 }
 
+//the lines of a function returned by ProcAddr2Sym::function_lines()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionLines {
+    pub decl_file: String, //where the function is declared (DW_AT_decl_file; ??:0 if unknown)
+    pub decl_line: u32, //the line of the function's name in its definition, or in its declaration for some member functions
+    pub last_line: u32, //the last line of the function's file with code in the function (its closing brace or close to it)
+}
+
+//the symbol & location returned by ProcAddr2Sym::proc_addr2line()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrInfo {
+    pub sym: SymInfo, //as returned by proc_addr2sym, with the file:line of the function's first address
+    pub file: String, //the source file of the address itself
+    pub line: u32, //the line number of the address itself
+    pub static_addr: u64, //the address itself in the executable's symbol table (0 if unknown)
+}
+
+//the files with the symbols of an executable file listed in the proc maps, returned by ProcAddr2Sym::mapped_files()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappedFile {
+    pub path: String, //as listed in the proc maps (before substitute-path)
+    pub file: String, //the file read - path after substitute-path & sysroot, or a file with the right build ID found elsewhere
+    pub build_id: Option<String>,
+    pub debug_file: Option<String>, //the separate debug info file, if the DWARF isn't in the file itself
+    pub debuglink: Option<String>, //the debug info file name in the file's .gnu_debuglink section
+}
+
+//a frame in the chain returned by ProcAddr2Sym::inline_frames()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InlineFrame {
+    pub func: String, //before c++filt
+    pub demangled_func: String, //after c++filt
+    pub file: String,
+    pub line: u32,
+}
+
 fn time2str(time: &SystemTime) -> String {
     let datetime: DateTime<Local> = (*time).into();
     datetime.format("%Y-%m-%d %H:%M:%S").to_string()
@@ -213,18 +577,68 @@ fn strip_clone(input: String) -> String {
     }
 }
 
+fn default_symbol_index_dir() -> Option<PathBuf> {
+    match (std::env::var("FUNTRACE_SYMBOL_INDEX_DIR"), std::env::var("XDG_CACHE_HOME"), std::env::var("HOME")) {
+        (Ok(index_dir), _, _) => Some(PathBuf::from(index_dir)).filter(|index_dir| !index_dir.as_os_str().is_empty()),
+        (_, Ok(cache_home), _) => Some(PathBuf::from(cache_home).join("funtrace-symbols")),
+        (_, _, Ok(home)) => Some(PathBuf::from(home).join(".cache/funtrace-symbols")),
+        _ => None,
+    }
+}
+
+//saves the symbol indexes of the executable files with functions looked up for the first time
+impl Drop for ProcAddr2Sym {
+    fn drop(&mut self) {
+        for meta in self.sym_cache.values() {
+            if let (true, Some(index_path)) = (meta.index_dirty, &meta.index_path) {
+                if let Err(e) = symbol_index::write_index(index_path, &meta.symbols, meta.dwarf_file.as_deref(), &meta.functions) {
+                    println!("WARNING: failed to save the symbol index of {} into {} - {}", meta.path, index_path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+impl Default for ProcAddr2Sym {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//the debug directories, followed by those under the sysroots (a free function rather than a method
+//so that it can be called while other fields of ProcAddr2Sym are borrowed)
+fn debug_dirs_under_sysroots(debug_dirs: &[String], sysroots: &[String]) -> Vec<String> {
+    let mut all_dirs = debug_dirs.to_vec();
+    for sysroot in sysroots {
+        all_dirs.extend(debug_dirs.iter().filter(|dir| dir.starts_with('/')).map(|dir| format!("{}{}", sysroot.trim_end_matches('/'), dir)));
+    }
+    all_dirs
+}
+
 impl ProcAddr2Sym {
     pub fn new() -> Self {
         ProcAddr2Sym { maps: Vec::new(), sym_cache: HashMap::new(), sym_missing: HashSet::new(), offset_cache: HashMap::new(), source_files: HashSet::new(),
-            subs_path: parse_substitute_path_json("substitute-path.json"), input_source: None }
+            subs_path: default_substitute_path(), input_source: None, find_source_lines: true,
+            instrumentation_cache: HashMap::new(), function_decls: HashMap::new(), read_units: HashSet::new(), split_dwarf: HashMap::new(),
+            debug_dirs: std::env::var("FUNTRACE_DEBUG_DIRS").unwrap_or("/usr/lib/debug".to_string()).split(':').filter(|dir| !dir.is_empty()).map(|dir| dir.to_string()).collect(),
+            sysroots: std::env::var("FUNTRACE_SYSROOT").unwrap_or_default().split(':').filter(|dir| !dir.is_empty()).map(|dir| dir.to_string()).collect(),
+            debuginfod: Debuginfod::from_env(), build_ids: HashMap::new(),
+            symbol_index_dir: default_symbol_index_dir(), report_symbol_index_hits: false, symtable: SymTable::default() }
     }
 
-    fn substitute_path(&self, path: String) -> String {
-        let mut s = path;
-        for subs in &self.subs_path {
-            s = s.replace(&subs.src, &subs.dst);
-        }
-        s
+    fn local_path(&self, kind: PathKind, path: String) -> String {
+        local_path(&self.subs_path, &self.sysroots, kind, path)
+    }
+
+    //reads the path substitution rules from this file rather than from $FUNTRACE_SUBSTITUTE_PATH or
+    //./substitute-path.json (see substitute_path.rs for the format); fails on a malformed rule
+    pub fn set_substitute_path(&mut self, file_name: &str) -> Result<(), String> {
+        self.subs_path = parse_substitute_path_json(file_name)?;
+        Ok(())
+    }
+
+    fn debug_dirs(&self) -> Vec<String> {
+        debug_dirs_under_sysroots(&self.debug_dirs, &self.sysroots)
     }
 
     // note that updating the maps doesn't invalidate sym_cache - we don't need to parse
@@ -236,93 +650,369 @@ impl ProcAddr2Sym {
         // not sure we need to sort them - /proc/self/maps appears already sorted - but can't hurt
         self.maps.sort_by_key(|map| map.address.0);
         self.offset_cache = HashMap::new();
+        //a trace without build IDs (the BUILDIDS chunk following PROCMAPS) can't be checked against the binaries
+        self.build_ids = HashMap::new();
+        //...and neither can it be decoded without the binaries, unless a SYMTABLE chunk follows as well
+        self.symtable = SymTable::default();
+    }
+
+    //the content of a BUILDIDS chunk: "<build ID in hex> <path>" lines for the executable files listed in
+    //the proc maps passed to set_proc_maps() before this call. the files opened to look up symbols are then
+    //checked against these build IDs, and files with the right build ID are searched for if they don't match
+    pub fn set_build_ids(&mut self, build_ids_data: &[u8]) {
+        for line in String::from_utf8_lossy(build_ids_data).lines() {
+            if let Some((build_id, path)) = line.split_once(' ') {
+                self.build_ids.insert(path.to_string(), build_id.to_string());
+            }
+        }
+    }
+
+    //the content of a SYMTABLE chunk (see symtable.rs), made by make_symtable() for the proc maps passed to
+    //set_proc_maps() before this call. the symbols of the addresses it lists are then taken from it rather
+    //than from the binaries, as are function_lines(), instrumentation() and the source code returned by symtable_source()
+    pub fn set_symtable(&mut self, symtable_data: &[u8]) {
+        let mut symtable = match SymTable::from_json(symtable_data) {
+            Ok(symtable) => symtable,
+            Err(e) => {
+                println!("WARNING: ignoring a malformed SYMTABLE - {}", e);
+                return;
+            }
+        };
+        //the source paths are those where the trace was taken, so we remap them like the paths in the debug info
+        //(once per file, since a sysroot is checked by looking for the file under it)
+        let mut local_paths: HashMap<String, String> = HashMap::new();
+        let mut local_source = |file: String| local_paths.entry(file).or_insert_with_key(|file| local_path(&self.subs_path, &self.sysroots, PathKind::Source, file.clone())).clone();
+        for sym in symtable.symbols.values_mut() {
+            sym.file = local_source(mem::take(&mut sym.file));
+        }
+        for lines in symtable.function_lines.values_mut() {
+            lines.decl_file = local_source(mem::take(&mut lines.decl_file));
+        }
+        symtable.sources = symtable.sources.into_iter().map(|(file, lines)| (local_source(file), lines)).collect();
+        self.symtable = symtable;
+    }
+
+    //a SYMTABLE chunk with the symbols of these addresses (in the proc maps passed to set_proc_maps()), and the
+    //source code of their functions if sources is true. addresses without a symbol are left out
+    pub fn make_symtable(&mut self, addresses: &[u64], sources: bool) -> Vec<u8> {
+        let mut symtable = SymTable::default();
+        let mut source_files: HashMap<String, Option<Vec<String>>> = HashMap::new();
+        for &address in addresses {
+            let sym = self.proc_addr2sym(address);
+            if sym.func == "??" {
+                continue;
+            }
+            let lines = self.function_lines(&sym);
+            if sources && sym.line > 0 {
+                //from a few lines above the function's first line (like funtrace2viz shows it if the declaration
+                //is unknown) or from its declaration if it's in the same file, to its last line
+                let last_line = lines.as_ref().map(|lines| lines.last_line).unwrap_or(sym.line);
+                let first_line = match lines {
+                    Some(ref lines) if lines.decl_file == sym.file && lines.decl_line > 0 => lines.decl_line.min(sym.line),
+                    _ => sym.line.saturating_sub(3).max(1),
+                };
+                let file_lines = source_files.entry(sym.file.clone()).or_insert_with(|| {
+                    fs::read(&sym.file).ok().map(|text| String::from_utf8_lossy(&text).lines().map(|line| line.to_string()).collect())
+                });
+                if let Some(file_lines) = file_lines {
+                    let snippet: &mut BTreeMap<u32, String> = symtable.sources.entry(sym.file.clone()).or_default();
+                    for line in first_line..=last_line.min(file_lines.len() as u32) {
+                        snippet.insert(line, file_lines[line as usize - 1].clone());
+                    }
+                }
+            }
+            if let Some(lines) = lines {
+                symtable.function_lines.insert((sym.executable_file.clone(), sym.static_addr), lines);
+            }
+            if !symtable.instrumentation.contains_key(&sym.executable_file) {
+                symtable.instrumentation.insert(sym.executable_file.clone(), self.instrumentation(&sym.executable_file));
+            }
+            symtable.symbols.insert(address, sym);
+        }
+        let missing: Vec<&String> = source_files.iter().filter(|(_, lines)| lines.is_none()).map(|(file, _)| file).collect();
+        if !missing.is_empty() {
+            println!("WARNING: couldn't read {} source files - their code is left out of the SYMTABLE", missing.len());
+        }
+        symtable.to_json()
+    }
+
+    //the source file as saved in the SYMTABLE passed to set_symtable(), with the lines outside the traced
+    //functions left empty. None if the SYMTABLE has no code from this file
+    pub fn symtable_source(&self, file: &str) -> Option<String> {
+        let lines = self.symtable.sources.get(file)?;
+        let last_line = *lines.keys().next_back()?;
+        Some((1..=last_line).map(|line| lines.get(&line).map(|text| text.as_str()).unwrap_or("")).collect::<Vec<_>>().join("\n") + "\n")
+    }
+
+    //the executable files listed in the proc maps passed to set_proc_maps() and the files their symbols are read from,
+    //loading them if they weren't loaded yet. files which couldn't be opened are left out
+    pub fn mapped_files(&mut self) -> Vec<MappedFile> {
+        let mut paths: Vec<(String, u64)> = Vec::new();
+        for map in &self.maps {
+            if let MMapPath::Path(path) = &map.pathname {
+                let path = path.to_string_lossy().to_string();
+                //(linux-vdso.so.1 is listed without a directory and has no file)
+                if path.starts_with('/') && !paths.iter().any(|(listed, _)| *listed == path) {
+                    paths.push((path, map.address.0));
+                }
+            }
+        }
+        paths.into_iter().filter_map(|(path, address)| {
+            let (pathstr, _) = self.static_address(address)?;
+            let meta = self.sym_cache.get(&pathstr)?;
+            let debuglink = Elf::parse(&meta.data).ok().and_then(|elf| debuglink(&elf, &meta.data)).map(|(name, _)| name);
+            Some(MappedFile { path, file: meta.data_file.clone(), build_id: meta.build_id.clone(), debug_file: meta.dwarf_file.clone(), debuglink })
+        }).collect()
     }
 
     pub fn unknown_symbol(&self) -> SymInfo {
-        return SymInfo { func: "??".to_string(), demangled_func: "??".to_string(), file: "??".to_string(), line: 0, executable_file: "??".to_string(), static_addr: 0, size: 0 };
+        SymInfo { func: "??".to_string(), demangled_func: "??".to_string(), file: "??".to_string(), line: 0, executable_file: "??".to_string(), static_addr: 0, size: 0 }
     }
 
-    pub fn proc_addr2sym(&mut self, proc_address: u64) -> SymInfo {
-        let unknown = self.unknown_symbol();
-        let map_opt = find_address_in_maps(proc_address, &self.maps);
-        if map_opt == None { return unknown; }
-        let map = map_opt.unwrap();
+    //finds the separate debug info file of a stripped executable file - by its build ID under the debug
+    //directories (debug_dir/.build-id/xx/yyyy.debug), or by its .gnu_debuglink file name next to the file,
+    //under its .debug subdirectory or under the debug directories (debug_dir/path/to/file/dir/name),
+    //checking the CRC in the latter case (like gdb does.) failing that, downloads it from debuginfod
+    fn find_debug_file(&mut self, path: &str, elf: &Elf, data: &[u8], build_id: Option<&str>) -> Option<(String, Mmap)> {
+        let open = |candidate: &str| File::open(candidate).ok().and_then(|file| unsafe { Mmap::map(&file) }.ok()).map(|data| (candidate.to_string(), data));
+        let build_id = build_id.filter(|id| id.len() > 2);
+        if let Some(build_id) = build_id {
+            for debug_dir in &self.debug_dirs() {
+                if let Some(debug_data) = open(&format!("{}/.build-id/{}/{}.debug", debug_dir, &build_id[..2], &build_id[2..])) {
+                    return Some(debug_data);
+                }
+            }
+        }
+        if let Some((name, crc)) = debuglink(elf, data) {
+            let dir = std::path::Path::new(path).parent().map(|dir| dir.to_string_lossy().to_string()).unwrap_or_default();
+            let mut candidates = vec![format!("{}/{}", dir, name), format!("{}/.debug/{}", dir, name)];
+            candidates.extend(self.debug_dirs().iter().map(|debug_dir| format!("{}{}/{}", debug_dir, dir, name)));
+            for candidate in candidates {
+                //the debuglink might name the file itself (in which case it's not stripped, but this can happen)
+                if candidate == path {
+                    continue;
+                }
+                if let Some((candidate, debug_data)) = open(&candidate) {
+                    if crc32(&debug_data) == crc {
+                        return Some((candidate, debug_data));
+                    }
+                    println!("WARNING: ignoring {} - its CRC doesn't match the .gnu_debuglink section of {}", candidate, path);
+                }
+            }
+        }
+        let debug_file = self.debuginfod.debuginfo(build_id?)?;
+        open(&debug_file.to_string_lossy())
+    }
 
-        let path_opt = match &map.pathname {
-            MMapPath::Path(p) => Some(p),
-            _ => None,
-        };
-        if path_opt == None { return unknown; }
-        let path = path_opt.unwrap();
+    //a copy of an executable file with the given build ID, for when the file at the path the process loaded it
+    //from is missing or was rebuilt: <debug dir>/.build-id/xx/yyyy (which distributions install as a link to
+    //the binary, alongside the .debug file), <debug dir>/<file name>, or a download from debuginfod
+    fn find_executable_file(&mut self, path: &str, build_id: &str) -> Option<(String, Mmap)> {
+        let open = |candidate: &str| File::open(candidate).ok().and_then(|file| unsafe { Mmap::map(&file) }.ok());
+        let name = std::path::Path::new(path).file_name()?.to_string_lossy().to_string();
+        let mut candidates = Vec::new();
+        for debug_dir in &self.debug_dirs() {
+            if build_id.len() > 2 {
+                candidates.push(format!("{}/.build-id/{}/{}", debug_dir, &build_id[..2], &build_id[2..]));
+            }
+            candidates.push(format!("{}/{}", debug_dir, name));
+        }
+        for candidate in candidates {
+            if let Some(data) = open(&candidate) {
+                if Elf::parse(&data).ok().and_then(|elf| crate::build_id(&elf, &data)).as_deref() == Some(build_id) {
+                    return Some((candidate, data));
+                }
+            }
+        }
+        let executable = self.debuginfod.executable(build_id)?.to_string_lossy().to_string();
+        open(&executable).map(|data| (executable, data))
+    }
 
-        let pathstr = self.substitute_path(path.to_string_lossy().to_string());
+    //a local copy of a source file of the function described by sym (as returned by proc_addr2sym), downloaded
+    //from debuginfod using the build ID of its executable file. for when sym.file can't be opened - note that
+    //the server is asked for sym.file after substitute-path.json was applied to it
+    pub fn fetch_source_file(&mut self, sym: &SymInfo) -> Option<String> {
+        let build_id = self.sym_cache.get(&sym.executable_file)?.build_id.clone()?;
+        self.debuginfod.source(&build_id, &sym.file).map(|path| path.to_string_lossy().to_string())
+    }
+
+    //finds the executable file mapped at proc_address, loading its symbols & debug info if it wasn't loaded yet;
+    //returns the file's path (after substitute-path & sysroot) and the static address corresponding to proc_address
+    fn static_address(&mut self, proc_address: u64) -> Option<(String, u64)> {
+        let map = find_address_in_maps(proc_address, &self.maps)?;
+        let MMapPath::Path(path) = &map.pathname else { return None };
+        let (map_start, map_offset) = (map.address.0, map.offset);
+
+        let expected_build_id = self.build_ids.get(&*path.to_string_lossy()).cloned();
+        let pathstr = self.local_path(PathKind::Binary, path.to_string_lossy().to_string());
         if self.sym_missing.contains(&pathstr) {
-            return unknown;
+            return None;
         }
         if !self.sym_cache.contains_key(&pathstr) {
-            let fileopt = File::open(pathstr.clone());
-            if fileopt.is_err() {
-                println!("WARNING: couldn't open executable file {} - you can remap paths using a substitute-path.json file in your working directory", pathstr);
-                self.sym_missing.insert(pathstr);
-                return unknown;
+            let mut data_file = pathstr.clone();
+            //a file we can't map (say, a directory, or a file truncated after we opened it) is treated like a missing file
+            let (mut buffer, open_error) = match File::open(&pathstr).and_then(|file| unsafe { Mmap::map(&file) }) {
+                Ok(data) => (Some(data), None),
+                Err(e) => (None, Some(e)),
+            };
+            if let Some(ref expected_build_id) = expected_build_id {
+                //a file with the wrong build ID would give wrong symbols, so we'd rather not use it
+                let actual_build_id = buffer.as_ref().and_then(|buffer| Elf::parse(buffer).ok().and_then(|elf| build_id(&elf, buffer)));
+                if buffer.is_some() && actual_build_id.as_ref() != Some(expected_build_id) {
+                    println!("ERROR: executable file {} has build ID {} but the traced process loaded build ID {} - looking for a file with the right build ID",
+                             pathstr, actual_build_id.as_deref().unwrap_or("(none)"), expected_build_id);
+                    buffer = None;
+                }
+                if buffer.is_none() {
+                    match self.find_executable_file(&pathstr, expected_build_id) {
+                        Some((found, data)) => {
+                            println!("  using {} for {} (build ID {})", found, pathstr, expected_build_id);
+                            buffer = Some(data);
+                            data_file = found;
+                        }
+                        None => {
+                            println!("ERROR: no file with build ID {} found for {} - its functions will show as ??; pass --debug-dir with a directory containing the right file", expected_build_id, pathstr);
+                            self.sym_missing.insert(pathstr);
+                            return None;
+                        }
+                    }
+                }
             }
-            let file = fileopt.unwrap();
-            if let Some(ref input_source) = self.input_source {
-                let modified = fs::metadata(pathstr.clone()).expect("failed to stat file").modified().expect("failed to get last modification timestamp");
-                if modified > input_source.modified {
-                    println!("WARNING: executable file {} last modified at {} - later than {} ({})", pathstr, time2str(&modified), input_source.path, time2str(&input_source.modified)); 
+            let Some(buffer) = buffer else {
+                println!("WARNING: couldn't open executable file {}{} - you can remap paths using a substitute-path.json file in your working directory, or pass --sysroot if the traced process ran in a container or a chroot", pathstr, open_error.map(|e| format!(" ({})", e)).unwrap_or_default());
+                self.sym_missing.insert(pathstr);
+                return None;
+            };
+            //a matching build ID tells more than the modification time
+            if let (Some(ref input_source), None) = (&self.input_source, &expected_build_id) {
+                match fs::metadata(&pathstr).and_then(|metadata| metadata.modified()) {
+                    Ok(modified) => if modified > input_source.modified {
+                        println!("WARNING: executable file {} last modified at {} - later than {} ({})", pathstr, time2str(&modified), input_source.path, time2str(&input_source.modified)); 
+                    },
+                    Err(e) => println!("WARNING: couldn't get the modification time of executable file {} - {}", pathstr, e),
                 }
             }
-            let buffer = unsafe { Mmap::map(&file).expect("failed to mmap executable file") };
-            let elf = Elf::parse(&buffer).expect("Failed to parse ELF");
-            let symbols = read_elf_symbols(&elf);
+            let elf = match Elf::parse(&buffer) {
+                Ok(elf) => elf,
+                Err(e) => {
+                    println!("WARNING: failed to parse executable file {} - {}", pathstr, e);
+                    self.sym_missing.insert(pathstr);
+                    return None;
+                }
+            };
             let program_headers = elf.program_headers.clone();
-            let object = object::File::parse(&*buffer).expect("Failed to parse ELF");
-            let ctx = addr2line::Context::new(&object).expect("Failed to create addr2line context");
-            self.sym_cache.insert(pathstr.clone(), ExecutableFileMetadata { program_headers, addr2line: ctx, symbols });
+            let (xray_sleds, xray_function_ids) = read_xray_instr_map(&elf, &buffer);
+            let build_id = build_id(&elf, &buffer);
+            let index_path = self.symbol_index_dir.as_ref().and_then(|index_dir| symbol_index::index_path(index_dir, &pathstr, build_id.as_deref()));
+            let mut index = index_path.as_ref().and_then(|index_path| symbol_index::read_index(index_path));
+            //for a stripped file, we take the symbols & DWARF from its separate debug info file if we find it;
+            //the static addresses are the same in both, and the program headers & code are the stripped file's
+            let mut debug_file = None;
+            if !has_debug_info(&elf) && index.as_ref().is_none_or(|index| index.dwarf_file.is_none()) {
+                debug_file = self.find_debug_file(&pathstr, &elf, &buffer, build_id.as_deref());
+                if debug_file.is_some() {
+                    index = None; //made before the debug info was installed
+                }
+            }
+            if let (Some(index_path), Some(_), true) = (&index_path, &index, self.report_symbol_index_hits) {
+                println!("  using the symbol index {} for {}", index_path.display(), pathstr);
+            }
+            //the symbols are read from the symbol index if we have one, and the DWARF is parsed when first needed
+            let (symbols, dwarf_file, functions, index_dirty) = match index {
+                Some(index) => (index.symbols, index.dwarf_file, index.functions, false),
+                None => {
+                    let mut symbols = read_elf_symbols(&elf);
+                    let mut dwarf_file = None;
+                    if let Some((debug_file, debug_data)) = debug_file {
+                        if let Ok(debug_elf) = Elf::parse(&debug_data) {
+                            symbols.extend(read_elf_symbols(&debug_elf));
+                            symbols.sort_by_key(|sym| sym.base_address);
+                            dwarf_file = Some(debug_file);
+                        }
+                    }
+                    (symbols, dwarf_file, HashMap::new(), true)
+                }
+            };
+            self.sym_cache.insert(pathstr.clone(), ExecutableFileMetadata { path: pathstr.clone(), data_file, program_headers, addr2line: OnceCell::new(), dwarf_file, symbols,
+                data: buffer, xray_sleds, xray_function_ids, build_id, functions, index_path, index_dirty });
         }
         let meta = self.sym_cache.get(&pathstr).unwrap();
 
-        if !self.offset_cache.contains_key(&map.address.0) {
+        if !self.offset_cache.contains_key(&map_start) {
             //find the program header containing the file offset of this mapping
             let mut found = false;
             for phdr in meta.program_headers.iter() {
-                if map.offset >= phdr.p_offset && map.offset < (phdr.p_offset + phdr.p_filesz) {
-                    let vaddr_offset = (map.offset - phdr.p_offset) + phdr.p_vaddr;
-                    self.offset_cache.insert(map.address.0, vaddr_offset);
+                if map_offset >= phdr.p_offset && map_offset < (phdr.p_offset + phdr.p_filesz) {
+                    let vaddr_offset = (map_offset - phdr.p_offset) + phdr.p_vaddr;
+                    self.offset_cache.insert(map_start, vaddr_offset);
                     found = true;
                     break;
                 }
             }
-            if !found { return unknown; } 
+            if !found { return None; } 
+        }
+        let vaddr_offset = self.offset_cache.get(&map_start).unwrap();
+        Some((pathstr, proc_address - map_start + vaddr_offset))
+    }
+
+    pub fn proc_addr2sym(&mut self, proc_address: u64) -> SymInfo {
+        if let Some(sym) = self.symtable.symbols.get(&proc_address) {
+            return sym.clone();
         }
-        let vaddr_offset = self.offset_cache.get(&map.address.0).unwrap();
-        let mut static_addr = proc_address - map.address.0 + vaddr_offset;
+        let unknown = self.unknown_symbol();
+        let Some((pathstr, mut static_addr)) = self.static_address(proc_address) else { return unknown };
+        let meta = self.sym_cache.get_mut(&pathstr).unwrap();
         let mut size = 0;
 
         let mut name = "??".to_string();
         let mut demangled_func = "??".to_string();
         let mut name_found = false;
 
-        if let Some(sym) = find_symbol(&meta.symbols, static_addr) {
+        //under XRay, the entry sled tells the function's entry address, which we use for finding its symbol -
+        //more reliable than the address inside the function for zero-sized or aliased symbols
+        let xray_function = find_xray_function(&meta.xray_sleds, static_addr);
+        if let Some(function) = xray_function {
+            static_addr = function;
+        }
+        let sym_opt = match xray_function {
+            Some(function) => find_function_symbol(&meta.symbols, function),
+            None => find_symbol(&meta.symbols, static_addr),
+        };
+        if let Some(sym) = sym_opt {
             name_found = true;
             name = sym.name.clone();
             static_addr = sym.base_address;
             size = sym.size; 
-            if let Ok(demsym) = cpp_demangle::Symbol::new(name.clone()) {
-                demangled_func = demsym.to_string();
-            }
-            else {
-                demangled_func = name.clone();
-            }
         }
 
-        let (file, linenum) = match meta.addr2line.find_location(static_addr) {
-            Ok(Some(location)) => (location.file.unwrap_or("??"), location.line.unwrap_or(0)),
-            _ => ("??",0),
+        //the demangled name & location of a function are kept in the symbol index, to be found there by later runs
+        let (file, linenum) = match meta.functions.get(&static_addr).filter(|_| name_found && self.find_source_lines) {
+            Some(function) => {
+                demangled_func = function.demangled_func.clone();
+                (function.file.clone(), function.line)
+            }
+            None => {
+                if name_found {
+                    demangled_func = match cpp_demangle::Symbol::new(name.clone()) {
+                        Ok(demsym) => demsym.to_string(),
+                        Err(_) => name.clone(),
+                    };
+                }
+                let (file, linenum) = if !self.find_source_lines { ("??".to_string(),0) } else {
+                    match meta.addr2line().find_location(static_addr) {
+                        Ok(Some(location)) => (location.file.unwrap_or("??").to_string(), location.line.unwrap_or(0)),
+                        _ => ("??".to_string(),0),
+                    }
+                };
+                if name_found && self.find_source_lines {
+                    meta.functions.insert(static_addr, IndexedFunction { demangled_func: demangled_func.clone(), file: file.clone(), line: linenum, lines: None });
+                    meta.index_dirty = true;
+                }
+                (file, linenum)
+            }
         };
-        let file = self.substitute_path(file.to_string());
-        if let Some(ref input_source) = self.input_source {
+        let file = local_path(&self.subs_path, &self.sysroots, PathKind::Source, file);
+        if let (true, Some(input_source)) = (self.find_source_lines, &self.input_source) {
             let file = file.clone();
             if !self.source_files.contains(&file) {
                 //don't warn if we can't access the file (maybe the source code isn't supposed to be
@@ -356,7 +1046,8 @@ impl ProcAddr2Sym {
             //
             //but it seems harmless to keep this code as fallback just in case
             //(in any case we use addr2line for the file:line info so "the object is already there".)
-            if let Ok(frames) = meta.addr2line.find_frames(static_addr).skip_all_loads() { 
+            let split_dwarf = self.split_dwarf.entry(pathstr.clone()).or_insert_with(|| SplitDwarf::new(&pathstr, &debug_dirs_under_sysroots(&self.debug_dirs, &self.sysroots)));
+            if let Ok(frames) = split_dwarf.resolve(meta.addr2line().find_frames(static_addr), |path| local_path(&self.subs_path, &self.sysroots, PathKind::Binary, path)) {
                 if let Ok(Some(frame)) = frames.last() {
                     if let Some(funref) = frame.function.as_ref() {
                         if let Ok(fname) = funref.raw_name() {
@@ -372,4 +1063,227 @@ impl ProcAddr2Sym {
         }
         SymInfo{func:strip_clone(name), demangled_func:strip_clone(demangled_func), file, line:linenum, executable_file:pathstr, static_addr, size}
     }
+
+    //like proc_addr2sym, but also returns the file:line of proc_address itself (rather than only
+    //those of the first address of its function.) for return addresses, pass the return address
+    //minus 1 to get the line of the call rather than the next line
+    pub fn proc_addr2line(&mut self, proc_address: u64) -> AddrInfo {
+        let sym = self.proc_addr2sym(proc_address);
+        let mut info = AddrInfo { sym, file: "??".to_string(), line: 0, static_addr: 0 };
+        let Some((pathstr, static_addr)) = self.static_address(proc_address) else { return info };
+        info.static_addr = static_addr;
+        if !self.find_source_lines {
+            return info;
+        }
+        let meta = self.sym_cache.get(&pathstr).unwrap();
+        if let Ok(Some(location)) = meta.addr2line().find_location(static_addr) {
+            info.file = self.local_path(PathKind::Source, location.file.unwrap_or("??").to_string());
+            info.line = location.line.unwrap_or(0);
+        }
+        info
+    }
+
+    //where the function described by sym (as returned by proc_addr2sym) is declared and where its code ends,
+    //according to the debug info - more precise than sym.line, which is the line of its first instruction
+    //(often the opening brace, or a line of the first function inlined into it.) None if there's no debug info
+    pub fn function_lines(&mut self, sym: &SymInfo) -> Option<FunctionLines> {
+        if !self.find_source_lines || sym.line == 0 {
+            return None;
+        }
+        if let Some(lines) = self.symtable.function_lines.get(&(sym.executable_file.clone(), sym.static_addr)) {
+            return Some(lines.clone());
+        }
+        let meta = self.sym_cache.get_mut(&sym.executable_file)?;
+        if let Some(lines) = meta.functions.get(&sym.static_addr).and_then(|function| function.lines.clone()) {
+            return Some(FunctionLines { decl_file: local_path(&self.subs_path, &self.sysroots, PathKind::Source, lines.decl_file), ..lines });
+        }
+        let decls = self.function_decls.entry(sym.executable_file.clone()).or_default();
+        //the declarations are read once per compilation unit
+        let split_dwarf = self.split_dwarf.entry(sym.executable_file.clone()).or_insert_with(|| SplitDwarf::new(&sym.executable_file, &debug_dirs_under_sysroots(&self.debug_dirs, &self.sysroots)));
+        if let Some((dwarf, unit)) = split_dwarf.resolve(meta.addr2line().find_dwarf_and_unit(sym.static_addr), |path| local_path(&self.subs_path, &self.sysroots, PathKind::Binary, path)) {
+            if self.read_units.insert((sym.executable_file.clone(), unit.dwo_id, unit.header.offset())) {
+                let skeleton = match (&unit.line_program, unit.dwo_id) {
+                    (None, Some(dwo_id)) => split_dwarf.skeleton_unit(dwo_id),
+                    _ => None,
+                };
+                let line_unit = match skeleton {
+                    Some((ref skeleton_dwarf, ref skeleton)) => (&**skeleton_dwarf, skeleton),
+                    None => (dwarf, unit),
+                };
+                if let Err(e) = read_function_decls((dwarf, unit), line_unit, decls) {
+                    println!("WARNING: failed to read function declarations from {} - {}", sym.executable_file, e);
+                }
+            }
+        }
+        let (decl_file, decl_line) = decls.get(&sym.static_addr).cloned().unwrap_or(("??".to_string(), 0));
+
+        //the last line of the function's file among those of its code (lines of inlined functions from other files don't count)
+        let first_file = meta.addr2line().find_location(sym.static_addr).ok()??.file?;
+        let mut last_line = sym.line;
+        if let Ok(locations) = meta.addr2line().find_location_range(sym.static_addr, sym.static_addr + sym.size) {
+            for (_, _, location) in locations {
+                if location.file == Some(first_file) {
+                    last_line = last_line.max(location.line.unwrap_or(0));
+                }
+            }
+        }
+        let lines = FunctionLines { decl_file, decl_line, last_line };
+        if let Some(function) = meta.functions.get_mut(&sym.static_addr) {
+            function.lines = Some(lines.clone());
+            meta.index_dirty = true;
+        }
+        Some(FunctionLines { decl_file: local_path(&self.subs_path, &self.sysroots, PathKind::Source, lines.decl_file), ..lines })
+    }
+
+    //the chain of functions inlined at proc_address, innermost first, each with the file:line of the address
+    //within it (for the innermost function) or of the call site of the function it inlined (for the others.)
+    //the last frame is the function proc_addr2sym would return (whose file:line would be those of its first
+    //address rather than proc_address.) empty if there's no debug info. for return addresses, pass the return
+    //address minus 1 to get the call site rather than the next line
+    pub fn inline_frames(&mut self, proc_address: u64) -> Vec<InlineFrame> {
+        let mut inline_frames = Vec::new();
+        let Some((pathstr, static_addr)) = self.static_address(proc_address) else { return inline_frames };
+        let meta = self.sym_cache.get(&pathstr).unwrap();
+        let split_dwarf = self.split_dwarf.entry(pathstr.clone()).or_insert_with(|| SplitDwarf::new(&pathstr, &debug_dirs_under_sysroots(&self.debug_dirs, &self.sysroots)));
+        let Ok(mut frames) = split_dwarf.resolve(meta.addr2line().find_frames(static_addr), |path| local_path(&self.subs_path, &self.sysroots, PathKind::Binary, path)) else { return inline_frames };
+        while let Ok(Some(frame)) = frames.next() {
+            let (mut func, mut demangled_func) = ("??".to_string(), "??".to_string());
+            if let Some(funref) = frame.function.as_ref() {
+                if let Ok(fname) = funref.raw_name() {
+                    func = fname.to_string();
+                    demangled_func = func.clone();
+                }
+                if let Ok(dname) = funref.demangle() {
+                    demangled_func = dname.to_string();
+                }
+            }
+            let (file, line) = match frame.location {
+                Some(location) => (location.file.unwrap_or("??"), location.line.unwrap_or(0)),
+                None => ("??", 0),
+            };
+            inline_frames.push(InlineFrame { func: strip_clone(func), demangled_func: strip_clone(demangled_func), file: file.to_string(), line });
+        }
+        for frame in &mut inline_frames {
+            frame.file = self.local_path(PathKind::Source, mem::take(&mut frame.file));
+        }
+        inline_frames
+    }
+
+    //the instrumentation methods used in an executable file (SymInfo::executable_file of a symbol returned by
+    //proc_addr2sym); empty if the file wasn't loaded or no instrumentation was found. several methods mean
+    //that different object files linked into the executable file were compiled with different flags
+    pub fn instrumentation(&mut self, executable_file: &str) -> Vec<Instrumentation> {
+        if let Some(methods) = self.instrumentation_cache.get(executable_file).or(self.symtable.instrumentation.get(executable_file)) {
+            return methods.clone();
+        }
+        let Some(meta) = self.sym_cache.get(executable_file) else { return Vec::new() };
+        let methods = match Elf::parse(&meta.data) {
+            Ok(elf) => detect_instrumentation(&elf, &meta.data),
+            Err(_) => Vec::new(),
+        };
+        self.instrumentation_cache.insert(executable_file.to_string(), methods.clone());
+        methods
+    }
+
+    //the XRay function ID of the function described by sym (as returned by proc_addr2sym), if it was
+    //instrumented by XRay. note that in shared objects, the XRay runtime adds an object ID assigned
+    //at load time to the function IDs in the upper bits; this returns the function ID without it
+    pub fn xray_function_id(&self, sym: &SymInfo) -> Option<u32> {
+        self.sym_cache.get(&sym.executable_file)?.xray_function_ids.get(&sym.static_addr).copied()
+    }
+
+    //the functions which the function described by sym (as returned by proc_addr2sym) jumps to rather
+    //than calls - that is, tail-calls - found by disassembling it (x86-64 only.) returns the static
+    //addresses of the jump targets; jumps to addresses other than the start of a function in the same
+    //executable file (such as PLT entries for functions in other shared objects) are ignored
+    pub fn tail_call_targets(&self, sym: &SymInfo) -> Vec<u64> {
+        let mut targets = Vec::new();
+        let Some(meta) = self.sym_cache.get(&sym.executable_file) else { return targets };
+        if sym.size == 0 {
+            return targets;
+        }
+        let Some(phdr) = meta.program_headers.iter().find(|phdr| phdr.p_type == goblin::elf::program_header::PT_LOAD
+                && sym.static_addr >= phdr.p_vaddr && sym.static_addr < phdr.p_vaddr + phdr.p_filesz) else { return targets };
+        let start = (sym.static_addr - phdr.p_vaddr + phdr.p_offset) as usize;
+        let end = (start + sym.size as usize).min(meta.data.len());
+        let func_end = sym.static_addr + sym.size;
+
+        let mut decoder = Decoder::with_ip(64, &meta.data[start..end], sym.static_addr, DecoderOptions::NONE);
+        for instruction in &mut decoder {
+            //only unconditional jumps - a conditional jump to another symbol's start is more likely a jump to
+            //the function's own "[clone .cold]" part than a tail call
+            if instruction.mnemonic() != Mnemonic::Jmp {
+                continue;
+            }
+            let target = instruction.near_branch_target();
+            if target == 0 || (target >= sym.static_addr && target < func_end) {
+                continue;
+            }
+            if meta.symbols.binary_search_by_key(&target, |s| s.base_address).is_ok() && !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+        targets
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    //a directory with empty files at the given relative paths, removed when dropped
+    pub(crate) struct TempTree(pub(crate) String);
+
+    impl TempTree {
+        pub(crate) fn new(name: &str, files: &[&str]) -> TempTree {
+            let root = std::env::temp_dir().join(format!("procaddr2sym-{}-{}", name, std::process::id()));
+            for file in files {
+                let path = root.join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, "").unwrap();
+            }
+            TempTree(root.to_string_lossy().to_string())
+        }
+
+        pub(crate) fn path(&self, file: &str) -> String {
+            format!("{}/{}", self.0, file)
+        }
+    }
+
+    impl Drop for TempTree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn sysroots_in_order() {
+        let tree = TempTree::new("sysroots", &["a/usr/lib/x.so", "b/usr/lib/x.so", "b/usr/lib/y.so", "b/src/y.c"]);
+        //a trailing slash is fine
+        let sysroots = vec![tree.path("a"), tree.path("b") + "/"];
+        let local = |kind, path: &str| local_path(&[], &sysroots, kind, path.to_string());
+        assert_eq!(local(PathKind::Binary, "/usr/lib/x.so"), tree.path("a/usr/lib/x.so"));
+        assert_eq!(local(PathKind::Binary, "/usr/lib/y.so"), tree.path("b/usr/lib/y.so"));
+        assert_eq!(local(PathKind::Source, "/src/y.c"), tree.path("b/src/y.c"));
+        //not found under any sysroot, or not absolute
+        assert_eq!(local(PathKind::Binary, "/usr/lib/z.so"), "/usr/lib/z.so");
+        assert_eq!(local(PathKind::Binary, "usr/lib/x.so"), "usr/lib/x.so");
+    }
+
+    #[test]
+    fn substitution_before_sysroots() {
+        let tree = TempTree::new("substitution-before-sysroots", &["root/usr/lib/x.so"]);
+        let rules_file = tree.path("substitute-path.json");
+        fs::write(&rules_file, r#"[{"prefix": "/build/lib", "replace": "/usr/lib"}]"#).unwrap();
+        let rules = parse_substitute_path_json(&rules_file).unwrap();
+        assert_eq!(local_path(&rules, &[tree.path("root")], PathKind::Binary, "/build/lib/x.so".to_string()), tree.path("root/usr/lib/x.so"));
+    }
+
+    #[test]
+    fn debug_dirs_then_sysroots() {
+        let debug_dirs = vec!["/usr/lib/debug".to_string(), "debug".to_string()];
+        let sysroots = vec!["/a".to_string(), "/b/".to_string()];
+        //relative debug directories aren't looked up under the sysroots
+        assert_eq!(debug_dirs_under_sysroots(&debug_dirs, &sysroots), vec!["/usr/lib/debug", "debug", "/a/usr/lib/debug", "/b/usr/lib/debug"]);
+    }
 }
@@ -0,0 +1,118 @@
+//split DWARF support (-gsplit-dwarf): an executable file then only has "skeleton" compilation units
+//with line tables, while the function DIEs are in .dwo files (one per object file, found using
+//the skeleton's DW_AT_comp_dir & DW_AT_dwo_name) or in a .dwp package combining them
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::rc::Rc;
+use std::sync::Arc;
+use addr2line::{LookupContinuation, LookupResult, SplitDwarfLoad};
+use addr2line::gimli::{self, EndianReader, Reader, RunTimeEndian};
+use addr2line::object::{self, Object, ObjectSection};
+use memmap2::Mmap;
+use crate::DwarfReader;
+
+pub(crate) struct SplitDwarf {
+    //where to look for the .dwp package: next to the executable file and under the debug directories
+    dwp_paths: Vec<String>,
+    dwp: Option<Option<gimli::DwarfPackage<DwarfReader>>>, //loaded on first use
+    dwos: HashMap<gimli::DwoId, Option<Arc<gimli::Dwarf<DwarfReader>>>>,
+    //the DWARF of the executable file itself with the skeleton units (addr2line::Context doesn't expose it)
+    parent: Option<Arc<gimli::Dwarf<DwarfReader>>>,
+}
+
+//the DWARF sections of a .dwo or .dwp file (named .debug_*.dwo)
+fn load_dwo_sections<T>(path: &str, load: impl FnOnce(&mut dyn FnMut(gimli::SectionId) -> gimli::Result<DwarfReader>) -> gimli::Result<T>) -> Option<T> {
+    let file = File::open(path).ok()?;
+    let data = unsafe { Mmap::map(&file) }.ok()?;
+    let object = object::File::parse(&*data).ok()?;
+    let endian = if object.is_little_endian() { RunTimeEndian::Little } else { RunTimeEndian::Big };
+    let mut section = |id: gimli::SectionId| -> gimli::Result<DwarfReader> {
+        let mut data = id.dwo_name().and_then(|name| object.section_by_name(name)).and_then(|section| section.uncompressed_data().ok()).unwrap_or(Cow::Borrowed(&[]));
+        //GNU dwp writes an empty .debug_tu_index with 0 slots, which gimli rejects; an index with
+        //no units is equivalent to a missing index section, which gimli accepts
+        if matches!(id, gimli::SectionId::DebugCuIndex | gimli::SectionId::DebugTuIndex) && data.len() >= 12 && data[8..12] == [0; 4] {
+            data = Cow::Borrowed(&[]);
+        }
+        Ok(EndianReader::new(Rc::from(&*data), endian))
+    };
+    match load(&mut section) {
+        Ok(loaded) => Some(loaded),
+        Err(e) => {
+            println!("WARNING: failed to load split DWARF from {} - {}", path, e);
+            None
+        }
+    }
+}
+
+impl SplitDwarf {
+    pub(crate) fn new(executable_file: &str, debug_dirs: &[String]) -> Self {
+        let mut dwp_paths = vec![format!("{}.dwp", executable_file)];
+        dwp_paths.extend(debug_dirs.iter().map(|debug_dir| format!("{}{}.dwp", debug_dir, executable_file)));
+        SplitDwarf { dwp_paths, dwp: None, dwos: HashMap::new(), parent: None }
+    }
+
+    //runs an addr2line lookup to completion, loading the split DWARF it asks for; substitute
+    //is applied to the .dwo paths (substitute-path.json & sysroot)
+    pub(crate) fn resolve<L: LookupContinuation<Buf = DwarfReader>>(&mut self, mut result: LookupResult<L>, substitute: impl Fn(String) -> String) -> L::Output {
+        loop {
+            match result {
+                LookupResult::Output(output) => return output,
+                LookupResult::Load { load, continuation } => {
+                    let dwo = self.load(&load, &substitute);
+                    result = continuation.resume(dwo);
+                }
+            }
+        }
+    }
+
+    //the skeleton unit of a split unit loaded by resolve(), together with the DWARF containing it. a split unit
+    //has no line table of its own - its DW_AT_decl_file indexes refer to the skeleton's
+    pub(crate) fn skeleton_unit(&self, dwo_id: gimli::DwoId) -> Option<(Arc<gimli::Dwarf<DwarfReader>>, gimli::Unit<DwarfReader>)> {
+        let parent = self.parent.as_ref()?;
+        let mut headers = parent.units();
+        while let Ok(Some(header)) = headers.next() {
+            if let Ok(unit) = parent.unit(header) {
+                if unit.dwo_id == Some(dwo_id) {
+                    return Some((parent.clone(), unit));
+                }
+            }
+        }
+        None
+    }
+
+    fn load(&mut self, load: &SplitDwarfLoad<DwarfReader>, substitute: &impl Fn(String) -> String) -> Option<Arc<gimli::Dwarf<DwarfReader>>> {
+        self.parent.get_or_insert_with(|| load.parent.clone());
+        if let Some(dwo) = self.dwos.get(&load.dwo_id) {
+            return dwo.clone();
+        }
+        if self.dwp.is_none() {
+            let dwp_path = self.dwp_paths.iter().find(|path| std::path::Path::new(path).exists());
+            let empty = EndianReader::new(Rc::from(&[][..]), RunTimeEndian::default());
+            self.dwp = Some(dwp_path.and_then(|path| load_dwo_sections(path, |section| gimli::DwarfPackage::load(section, empty))));
+        }
+        let mut dwo = match self.dwp.as_ref().unwrap() {
+            Some(dwp) => dwp.find_cu(load.dwo_id, &load.parent).ok().flatten(),
+            None => None,
+        };
+        if dwo.is_none() {
+            //the .dwo file, relative to the compilation directory unless it's an absolute path
+            let to_string = |r: &Option<DwarfReader>| r.as_ref().and_then(|r| r.to_string_lossy().ok().map(|s| s.into_owned()));
+            if let Some(name) = to_string(&load.path) {
+                let path = match to_string(&load.comp_dir) {
+                    Some(comp_dir) if !name.starts_with('/') => format!("{}/{}", comp_dir, name),
+                    _ => name,
+                };
+                let path = substitute(path);
+                dwo = load_dwo_sections(&path, |section| gimli::Dwarf::load(section));
+                match dwo {
+                    Some(ref mut dwo) => dwo.make_dwo(&load.parent),
+                    None => println!("WARNING: couldn't open split DWARF file {} - you can remap paths using a substitute-path.json file in your working directory", path),
+                }
+            }
+        }
+        let dwo = dwo.map(Arc::new);
+        self.dwos.insert(load.dwo_id, dwo.clone());
+        dwo
+    }
+}
@@ -0,0 +1,230 @@
+//substitute-path.json: rules remapping the paths of binaries & source files in the trace and the debug info to
+//where they are locally. it's a JSON array of rules, applied in order:
+//
+//  ["/build/dir/", "/home/user/dir/"]   - replace every occurrence of a substring (the original format)
+//  {"prefix": "/build/dir", "replace": "/home/user/dir"}   - replace a prefix ending at a path component boundary
+//  {"regex": "^/build/([^/]+)/", "replace": "/home/user/$1/"}   - replace the first match, with $1... for the captures
+//
+//in the object rules, "replace" can also be an array of candidates, tried in order until one names an existing file,
+//and "applies_to" can restrict the rule to "binaries" (executable files, shared objects & split DWARF files) or
+//"sources". the first object rule producing an existing file wins; if none does, the path produced by the first
+//matching rule is used
+use std::fs;
+use regex::Regex;
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PathKind {
+    Binary,
+    Source,
+}
+
+#[derive(Debug)]
+enum Pattern {
+    Substring(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+pub(crate) struct SubsRule {
+    pattern: Pattern,
+    replace: Vec<String>,
+    applies_to: Option<PathKind>,
+}
+
+impl SubsRule {
+    //the candidate paths this rule maps the path to, or None if it doesn't match
+    fn candidates(&self, path: &str) -> Option<Vec<String>> {
+        match &self.pattern {
+            Pattern::Substring(src) => Some(vec![path.replace(src, &self.replace[0])]),
+            Pattern::Prefix(prefix) => {
+                let rest = path.strip_prefix(prefix.as_str())?;
+                if !(prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')) {
+                    return None; //"/build/dir" shouldn't match "/build/dir2/file"
+                }
+                Some(self.replace.iter().map(|replace| format!("{}{}", replace, rest)).collect())
+            }
+            Pattern::Regex(regex) => {
+                if !regex.is_match(path) {
+                    return None;
+                }
+                Some(self.replace.iter().map(|replace| regex.replace(path, replace.as_str()).into_owned()).collect())
+            }
+        }
+    }
+}
+
+pub(crate) fn substitute_path(rules: &[SubsRule], kind: PathKind, path: String) -> String {
+    let mut path = path;
+    let mut first_match = None;
+    for rule in rules {
+        if rule.applies_to.is_some_and(|applies_to| applies_to != kind) {
+            continue;
+        }
+        let Some(candidates) = rule.candidates(&path) else { continue };
+        //the original [src, dst] pairs are all applied one after another, like they always were
+        if let Pattern::Substring(_) = rule.pattern {
+            path = candidates.into_iter().next().unwrap();
+            continue;
+        }
+        if let Some(found) = candidates.iter().find(|candidate| fs::metadata(candidate).is_ok()) {
+            return found.clone();
+        }
+        first_match.get_or_insert(candidates.into_iter().next().unwrap());
+    }
+    first_match.unwrap_or(path)
+}
+
+fn parse_rule(rule: &Value) -> Result<SubsRule, String> {
+    if let Some(pair) = rule.as_array() {
+        return match pair.as_slice() {
+            [Value::String(src), Value::String(dst)] => Ok(SubsRule { pattern: Pattern::Substring(src.clone()), replace: vec![dst.clone()], applies_to: None }),
+            _ => Err("expected a pair of strings [\"from\", \"to\"]".to_string()),
+        };
+    }
+    let Some(rule) = rule.as_object() else { return Err("expected a [\"from\", \"to\"] pair or an object".to_string()) };
+    if let Some(key) = rule.keys().find(|key| !["prefix", "regex", "replace", "applies_to"].contains(&key.as_str())) {
+        return Err(format!("unknown key \"{}\" (expecting prefix, regex, replace & applies_to)", key));
+    }
+    let string = |rule: &Map<String, Value>, key: &str| match rule.get(key) {
+        None => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(format!("\"{}\" should be a string", key)),
+    };
+    let pattern = match (string(rule, "prefix")?, string(rule, "regex")?) {
+        (Some(prefix), None) => Pattern::Prefix(prefix),
+        (None, Some(regex)) => Pattern::Regex(Regex::new(&regex).map_err(|e| format!("bad regex - {}", e))?),
+        (Some(_), Some(_)) => return Err("a rule can't have both \"prefix\" and \"regex\"".to_string()),
+        (None, None) => return Err("a rule needs either \"prefix\" or \"regex\"".to_string()),
+    };
+    let replace = match rule.get("replace") {
+        Some(Value::String(replace)) => vec![replace.clone()],
+        Some(Value::Array(candidates)) if !candidates.is_empty() => {
+            candidates.iter().map(|candidate| candidate.as_str().map(|s| s.to_string())).collect::<Option<Vec<String>>>()
+                .ok_or("\"replace\" should be a string or an array of strings")?
+        }
+        Some(_) => return Err("\"replace\" should be a string or a non-empty array of strings".to_string()),
+        None => return Err("a rule needs \"replace\"".to_string()),
+    };
+    let applies_to = match string(rule, "applies_to")?.as_deref() {
+        None => None,
+        Some("binaries") => Some(PathKind::Binary),
+        Some("sources") => Some(PathKind::Source),
+        Some(other) => return Err(format!("\"applies_to\" should be \"binaries\" or \"sources\", not \"{}\"", other)),
+    };
+    Ok(SubsRule { pattern, replace, applies_to })
+}
+
+pub(crate) fn parse_substitute_path_json(file_name: &str) -> Result<Vec<SubsRule>, String> {
+    let json_str = fs::read_to_string(file_name).map_err(|e| format!("failed to read {} - {}", file_name, e))?;
+    let json_value: Value = serde_json::from_str(&json_str).map_err(|e| format!("failed to parse the JSON in {} - {}", file_name, e))?;
+    let Some(rules) = json_value.as_array() else { return Err(format!("{} should contain an array of rules", file_name)) };
+    rules.iter().enumerate().map(|(i, rule)| parse_rule(rule).map_err(|e| format!("{}: rule {} ({}): {}", file_name, i + 1, rule, e))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempTree;
+
+    fn rules(json: &str) -> Vec<SubsRule> {
+        let rules: Value = serde_json::from_str(json).unwrap();
+        rules.as_array().unwrap().iter().map(|rule| parse_rule(rule).unwrap()).collect()
+    }
+
+    fn substitute(json: &str, kind: PathKind, path: &str) -> String {
+        substitute_path(&rules(json), kind, path.to_string())
+    }
+
+    #[test]
+    fn substring_pairs() {
+        //replaced anywhere in the path, including in the middle of a component
+        let pair = r#"[["/build/dir", "/src"]]"#;
+        assert_eq!(substitute(pair, PathKind::Source, "/build/dir/a.c"), "/src/a.c");
+        assert_eq!(substitute(pair, PathKind::Source, "/build/dir2/a.c"), "/src2/a.c");
+        assert_eq!(substitute(pair, PathKind::Source, "/x/build/dir/a.c"), "/x/src/a.c");
+        //all the pairs are applied, each to the result of the previous ones
+        assert_eq!(substitute(r#"[["/a/", "/b/"], ["/b/", "/c/"]]"#, PathKind::Binary, "/a/x.so"), "/c/x.so");
+    }
+
+    #[test]
+    fn prefix_at_a_component_boundary() {
+        let prefix = r#"[{"prefix": "/build/dir", "replace": "/src"}]"#;
+        assert_eq!(substitute(prefix, PathKind::Source, "/build/dir/a.c"), "/src/a.c");
+        assert_eq!(substitute(prefix, PathKind::Source, "/build/dir"), "/src");
+        assert_eq!(substitute(prefix, PathKind::Source, "/build/dir2/a.c"), "/build/dir2/a.c");
+        assert_eq!(substitute(prefix, PathKind::Source, "/x/build/dir/a.c"), "/x/build/dir/a.c");
+        //a prefix ending with a slash ends at a boundary
+        assert_eq!(substitute(r#"[{"prefix": "/build/", "replace": "/src/"}]"#, PathKind::Source, "/build/a.c"), "/src/a.c");
+    }
+
+    #[test]
+    fn regex_captures() {
+        let regex = r#"[{"regex": "^/build/([^/]+)/(.*)$", "replace": "/home/$1/src/$2"}]"#;
+        assert_eq!(substitute(regex, PathKind::Source, "/build/proj/lib/a.c"), "/home/proj/src/lib/a.c");
+        assert_eq!(substitute(regex, PathKind::Source, "/other/proj/a.c"), "/other/proj/a.c");
+        //only the first match is replaced
+        assert_eq!(substitute(r#"[{"regex": "o", "replace": "0"}]"#, PathKind::Source, "/foo"), "/f0o");
+    }
+
+    #[test]
+    fn applies_to() {
+        let rules = r#"[{"prefix": "/build", "replace": "/bin", "applies_to": "binaries"},
+                        {"prefix": "/build", "replace": "/src", "applies_to": "sources"}]"#;
+        assert_eq!(substitute(rules, PathKind::Binary, "/build/a.so"), "/bin/a.so");
+        assert_eq!(substitute(rules, PathKind::Source, "/build/a.c"), "/src/a.c");
+    }
+
+    #[test]
+    fn candidate_fallbacks() {
+        let tree = TempTree::new("candidate-fallbacks", &["second/a.c", "third/a.c", "other/b.c"]);
+        //the first candidate naming an existing file
+        let candidates = format!(r#"[{{"prefix": "/build", "replace": ["{}", "{}", "{}"]}}]"#, tree.path("first"), tree.path("second"), tree.path("third"));
+        assert_eq!(substitute(&candidates, PathKind::Source, "/build/a.c"), tree.path("second/a.c"));
+        //...or the first candidate if none exists
+        assert_eq!(substitute(&candidates, PathKind::Source, "/build/c.c"), tree.path("first/c.c"));
+        //the first rule producing an existing file wins, even if an earlier rule matched
+        let rules = format!(r#"[{{"prefix": "/build", "replace": "{}"}}, {{"regex": "^/build", "replace": "{}"}}]"#, tree.path("first"), tree.path("other"));
+        assert_eq!(substitute(&rules, PathKind::Source, "/build/b.c"), tree.path("other/b.c"));
+        //...and otherwise the first matching rule's path is used
+        assert_eq!(substitute(&rules, PathKind::Source, "/build/c.c"), tree.path("first/c.c"));
+    }
+
+    #[test]
+    fn malformed_rules() {
+        for (rule, error) in [
+            (r#"["/a"]"#, "expected a pair of strings"),
+            (r#""/a""#, "expected a [\"from\", \"to\"] pair or an object"),
+            (r#"{"prefix": "/a", "replace": "/b", "replcae": "/c"}"#, "unknown key \"replcae\""),
+            (r#"{"prefix": 1, "replace": "/b"}"#, "\"prefix\" should be a string"),
+            (r#"{"prefix": "/a", "regex": "a", "replace": "/b"}"#, "can't have both"),
+            (r#"{"replace": "/b"}"#, "needs either \"prefix\" or \"regex\""),
+            (r#"{"regex": "(", "replace": "/b"}"#, "bad regex"),
+            (r#"{"prefix": "/a"}"#, "needs \"replace\""),
+            (r#"{"prefix": "/a", "replace": []}"#, "non-empty array"),
+            (r#"{"prefix": "/a", "replace": ["/b", 1]}"#, "a string or an array of strings"),
+            (r#"{"prefix": "/a", "replace": "/b", "applies_to": "all"}"#, "not \"all\""),
+        ] {
+            let e = parse_rule(&serde_json::from_str(rule).unwrap()).unwrap_err();
+            assert!(e.contains(error), "{} - expected `{}' in `{}'", rule, error, e);
+        }
+    }
+
+    #[test]
+    fn malformed_files() {
+        let tree = TempTree::new("malformed-files", &[]);
+        fs::create_dir_all(&tree.0).unwrap();
+        let file = tree.path("substitute-path.json");
+        for (json, error) in [
+            (r#"[["/a", "/b"], {"prefix": "/a"}]"#, ": rule 2 ({\"prefix\":\"/a\"}): a rule needs \"replace\""),
+            (r#"{"prefix": "/a", "replace": "/b"}"#, "should contain an array of rules"),
+            (r#"[["/a", "/b"]"#, "failed to parse the JSON"),
+        ] {
+            fs::write(&file, json).unwrap();
+            let e = parse_substitute_path_json(&file).unwrap_err();
+            assert!(e.contains(&file) && e.contains(error), "{} - expected `{}' in `{}'", json, error, e);
+        }
+        assert!(parse_substitute_path_json(&tree.path("missing.json")).unwrap_err().starts_with("failed to read"));
+    }
+}
@@ -0,0 +1,132 @@
+//a persistent per-executable-file index of the symbols and of the function locations resolved from the debug info,
+//so that decoding more traces of the same binaries (or running funcount2sym on them) needn't parse their symbol
+//tables & DWARF again. the index is a little-endian binary file:
+//
+//  "FTSYMIDX" version:u32 dwarf_file:str symbol_count:u64 (address:u64 size:u64 name:str)*
+//  function_count:u64 (address:u64 demangled_func:str file:str line:u32 has_lines:u8 [decl_file:str decl_line:u32 last_line:u32])*
+//
+//with str being a u32 length followed by UTF-8 bytes. paths are stored before substitute-path.json is applied
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use crate::{FunctionLines, Symbol};
+
+const MAGIC: &[u8; 8] = b"FTSYMIDX";
+//2: the decl lines of functions with DW_AT_ranges, which version 1 indexes have as unknown
+const VERSION: u32 = 2;
+
+//what we know about a function at a symbol's address, filled as it's looked up
+pub(crate) struct IndexedFunction {
+    pub demangled_func: String,
+    pub file: String, //the location of the function's first address (??:0 if unknown)
+    pub line: u32,
+    pub lines: Option<FunctionLines>, //ProcAddr2Sym::function_lines(), once it was called
+}
+
+pub(crate) struct SymbolIndex {
+    pub symbols: Vec<Symbol>,
+    pub dwarf_file: Option<String>, //the separate debug info file with the DWARF, if it's not the executable file itself
+    pub functions: HashMap<u64, IndexedFunction>,
+}
+
+//the index file of an executable file: named by its build ID if it has one, and otherwise by its path,
+//modification time and size
+pub(crate) fn index_path(index_dir: &Path, path: &str, build_id: Option<&str>) -> Option<PathBuf> {
+    if let Some(build_id) = build_id {
+        return Some(index_dir.join(format!("{}.idx", build_id)));
+    }
+    let meta = fs::metadata(path).ok()?;
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let name = Path::new(path).file_name()?.to_string_lossy();
+    Some(index_dir.join(format!("{}-{:08x}-{}-{}.idx", name, crate::crc32(path.as_bytes()), mtime, meta.len())))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, n: usize) -> Option<&[u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(n);
+        self.data = rest;
+        Some(bytes)
+    }
+    fn u8(&mut self) -> Option<u8> { Some(self.bytes(1)?[0]) }
+    fn u32(&mut self) -> Option<u32> { Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?)) }
+    fn u64(&mut self) -> Option<u64> { Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?)) }
+    fn str(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).ok()
+    }
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+//None if there's no index file or it's unreadable (in which case it's rebuilt)
+pub(crate) fn read_index(path: &Path) -> Option<SymbolIndex> {
+    let data = fs::read(path).ok()?;
+    let mut r = Reader { data: &data };
+    if r.bytes(MAGIC.len())? != MAGIC || r.u32()? != VERSION {
+        return None;
+    }
+    let dwarf_file = Some(r.str()?).filter(|file| !file.is_empty());
+    let symbol_count = r.u64()?;
+    let mut symbols = Vec::new();
+    for _ in 0..symbol_count {
+        symbols.push(Symbol { base_address: r.u64()?, size: r.u64()?, name: r.str()? });
+    }
+    let function_count = r.u64()?;
+    let mut functions = HashMap::new();
+    for _ in 0..function_count {
+        let address = r.u64()?;
+        let (demangled_func, file, line) = (r.str()?, r.str()?, r.u32()?);
+        let lines = match r.u8()? {
+            0 => None,
+            _ => Some(FunctionLines { decl_file: r.str()?, decl_line: r.u32()?, last_line: r.u32()? }),
+        };
+        functions.insert(address, IndexedFunction { demangled_func, file, line, lines });
+    }
+    Some(SymbolIndex { symbols, dwarf_file, functions })
+}
+
+pub(crate) fn write_index(path: &Path, symbols: &[Symbol], dwarf_file: Option<&str>, functions: &HashMap<u64, IndexedFunction>) -> io::Result<()> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    put_str(&mut out, dwarf_file.unwrap_or(""));
+    out.extend_from_slice(&(symbols.len() as u64).to_le_bytes());
+    for sym in symbols {
+        out.extend_from_slice(&sym.base_address.to_le_bytes());
+        out.extend_from_slice(&sym.size.to_le_bytes());
+        put_str(&mut out, &sym.name);
+    }
+    out.extend_from_slice(&(functions.len() as u64).to_le_bytes());
+    for (address, function) in functions {
+        out.extend_from_slice(&address.to_le_bytes());
+        put_str(&mut out, &function.demangled_func);
+        put_str(&mut out, &function.file);
+        out.extend_from_slice(&function.line.to_le_bytes());
+        match function.lines {
+            None => out.push(0),
+            Some(ref lines) => {
+                out.push(1);
+                put_str(&mut out, &lines.decl_file);
+                out.extend_from_slice(&lines.decl_line.to_le_bytes());
+                out.extend_from_slice(&lines.last_line.to_le_bytes());
+            }
+        }
+    }
+    //written under a temporary name first so that concurrent runs never see a partially written index
+    fs::create_dir_all(path.parent().unwrap())?;
+    let tmp = path.with_extension(format!("{}.part", std::process::id()));
+    fs::write(&tmp, &out)?;
+    fs::rename(&tmp, path)
+}
@@ -0,0 +1,89 @@
+//the SYMTABLE chunk added to trace files by funsymbolize: the symbols of the traced addresses, resolved where
+//the trace was taken, so that the trace can be decoded on machines without the binaries. it's a JSON object:
+//
+//  {"symbols": {"<address in hex>": {"func": ..., "demangled_func": ..., "file": ..., "line": ..., "executable_file": ...,
+//                                    "static_addr": ..., "size": ..., "decl_file": ..., "decl_line": ..., "last_line": ...}, ...},
+//   "instrumentation": {"<executable file>": ["-pg", ...], ...},
+//   "sources": {"<source file>": {"<line number>": "<line text>", ...}, ...}}
+//
+//decl_file, decl_line & last_line (see ProcAddr2Sym::function_lines()) are only there if the debug info had them;
+//"sources" is optional, with the lines of the traced functions from a few lines above their declaration to their last line
+use std::collections::{BTreeMap, HashMap};
+use serde_json::{Map, Value};
+use crate::{FunctionLines, Instrumentation, SymInfo};
+
+#[derive(Default)]
+pub(crate) struct SymTable {
+    pub symbols: HashMap<u64, SymInfo>,
+    pub function_lines: HashMap<(String, u64), FunctionLines>, //by (executable file, static address)
+    pub instrumentation: HashMap<String, Vec<Instrumentation>>, //by executable file
+    pub sources: HashMap<String, BTreeMap<u32, String>>, //source file -> line number -> line
+}
+
+impl SymTable {
+    pub(crate) fn to_json(&self) -> Vec<u8> {
+        let mut symbols = Map::new();
+        for (address, sym) in &self.symbols {
+            let mut entry = Map::new();
+            entry.insert("func".to_string(), Value::from(sym.func.clone()));
+            entry.insert("demangled_func".to_string(), Value::from(sym.demangled_func.clone()));
+            entry.insert("file".to_string(), Value::from(sym.file.clone()));
+            entry.insert("line".to_string(), Value::from(sym.line));
+            entry.insert("executable_file".to_string(), Value::from(sym.executable_file.clone()));
+            entry.insert("static_addr".to_string(), Value::from(sym.static_addr));
+            entry.insert("size".to_string(), Value::from(sym.size));
+            if let Some(lines) = self.function_lines.get(&(sym.executable_file.clone(), sym.static_addr)) {
+                entry.insert("decl_file".to_string(), Value::from(lines.decl_file.clone()));
+                entry.insert("decl_line".to_string(), Value::from(lines.decl_line));
+                entry.insert("last_line".to_string(), Value::from(lines.last_line));
+            }
+            symbols.insert(format!("{:x}", address), Value::Object(entry));
+        }
+        let mut json = Map::new();
+        json.insert("symbols".to_string(), Value::Object(symbols));
+        //(serde_json's Map is sorted by key, so the output is deterministic)
+        let instrumentation = self.instrumentation.iter().map(|(file, methods)| {
+            (file.clone(), Value::from(methods.iter().map(|method| method.to_string()).collect::<Vec<String>>()))
+        }).collect();
+        json.insert("instrumentation".to_string(), Value::Object(instrumentation));
+        if !self.sources.is_empty() {
+            let sources = self.sources.iter().map(|(file, lines)| {
+                (file.clone(), Value::Object(lines.iter().map(|(num, line)| (num.to_string(), Value::from(line.clone()))).collect()))
+            }).collect();
+            json.insert("sources".to_string(), Value::Object(sources));
+        }
+        Value::Object(json).to_string().into_bytes()
+    }
+
+    pub(crate) fn from_json(data: &[u8]) -> Result<SymTable, String> {
+        let json: Value = serde_json::from_slice(data).map_err(|e| e.to_string())?;
+        let mut symtable = SymTable::default();
+        let symbols = json.get("symbols").and_then(|symbols| symbols.as_object()).ok_or("no symbols object")?;
+        for (address, entry) in symbols {
+            let address = u64::from_str_radix(address, 16).map_err(|_| format!("bad address `{}'", address))?;
+            let str_field = |name: &str| entry.get(name).and_then(|value| value.as_str()).map(|s| s.to_string()).ok_or(format!("no {} at {:x}", name, address));
+            let u64_field = |name: &str| entry.get(name).and_then(|value| value.as_u64()).ok_or(format!("no {} at {:x}", name, address));
+            let sym = SymInfo { func: str_field("func")?, demangled_func: str_field("demangled_func")?, file: str_field("file")?, line: u64_field("line")? as u32,
+                executable_file: str_field("executable_file")?, static_addr: u64_field("static_addr")?, size: u64_field("size")? };
+            if let (Ok(decl_file), Ok(decl_line), Ok(last_line)) = (str_field("decl_file"), u64_field("decl_line"), u64_field("last_line")) {
+                symtable.function_lines.insert((sym.executable_file.clone(), sym.static_addr), FunctionLines { decl_file, decl_line: decl_line as u32, last_line: last_line as u32 });
+            }
+            symtable.symbols.insert(address, sym);
+        }
+        if let Some(instrumentation) = json.get("instrumentation").and_then(|instrumentation| instrumentation.as_object()) {
+            for (file, methods) in instrumentation {
+                let methods = methods.as_array().ok_or(format!("bad instrumentation of {}", file))?;
+                let methods = methods.iter().filter_map(|method| Instrumentation::from_name(method.as_str()?)).collect();
+                symtable.instrumentation.insert(file.clone(), methods);
+            }
+        }
+        if let Some(sources) = json.get("sources").and_then(|sources| sources.as_object()) {
+            for (file, lines) in sources {
+                let lines = lines.as_object().ok_or(format!("bad source lines of {}", file))?;
+                let lines = lines.iter().filter_map(|(num, line)| Some((num.parse().ok()?, line.as_str()?.to_string()))).collect();
+                symtable.sources.insert(file.clone(), lines);
+            }
+        }
+        Ok(symtable)
+    }
+}
@@ -0,0 +1,138 @@
+//reading funtrace.raw & funcount.txt, shared by funtrace2viz, funsymbolize, funpack & funcount2sym.
+//
+//funtrace.raw consists of chunks with an 8-byte magic string telling the chunk type, followed by an
+//8-byte length field and then contents of that length. funcount.txt is text:
+//
+//  FUNCOUNT\nPROCMAPS\n<maps>[BUILDIDS\n<build IDs>]COUNTS\n<0xaddress count lines>[SYMTABLE\n<JSON line>\n]
+use std::collections::BTreeSet;
+use std::io::{self, Read};
+
+pub const MAGIC_LEN: usize = 8;
+pub const LENGTH_LEN: usize = 8;
+//a TRACEBUF entry is an address and a cycle count, both u64
+pub const ENTRY_LEN: usize = 16;
+
+//the high bits of TRACEBUF entry addresses: returns have RETURN_BIT set, or RETURN_WITH_CALLER_ADDRESS_BIT
+//if they log the caller's address; catches have both. calls returning when an exception is thrown
+//have CALL_RETURNING_UPON_THROW_BIT
+pub const RETURN_BIT: i32 = 63;
+pub const RETURN_WITH_CALLER_ADDRESS_BIT: i32 = 62;
+pub const CATCH_MASK: u64 = (1<<RETURN_BIT)|(1<<RETURN_WITH_CALLER_ADDRESS_BIT);
+pub const CALL_RETURNING_UPON_THROW_BIT: i32 = 61;
+pub const ADDRESS_MASK: u64 = !(CATCH_MASK | (1<<CALL_RETURNING_UPON_THROW_BIT));
+
+pub struct Chunk {
+    pub magic: [u8; MAGIC_LEN],
+    pub content: Vec<u8>,
+}
+
+fn invalid(what: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+//returns None at the end of the input
+pub fn read_chunk(input: &mut impl Read) -> io::Result<Option<Chunk>> {
+    let mut header = Vec::with_capacity(MAGIC_LEN + LENGTH_LEN);
+    input.take((MAGIC_LEN + LENGTH_LEN) as u64).read_to_end(&mut header)?;
+    if header.is_empty() {
+        return Ok(None);
+    }
+    if header.len() < MAGIC_LEN + LENGTH_LEN {
+        return Err(invalid(format!("truncated chunk header {:?}", String::from_utf8_lossy(&header))));
+    }
+    let magic: [u8; MAGIC_LEN] = header[..MAGIC_LEN].try_into().unwrap();
+    let length = u64::from_ne_bytes(header[MAGIC_LEN..].try_into().unwrap());
+    let mut content = Vec::new();
+    input.take(length).read_to_end(&mut content)?;
+    if (content.len() as u64) < length {
+        return Err(invalid(format!("truncated {} chunk - {} bytes instead of {}", String::from_utf8_lossy(&magic), content.len(), length)));
+    }
+    Ok(Some(Chunk { magic, content }))
+}
+
+pub fn read_chunks(mut data: &[u8]) -> io::Result<Vec<Chunk>> {
+    let mut chunks = Vec::new();
+    while let Some(chunk) = read_chunk(&mut data)? {
+        chunks.push(chunk);
+    }
+    Ok(chunks)
+}
+
+pub fn write_chunk(out: &mut Vec<u8>, magic: &[u8], content: &[u8]) {
+    out.extend_from_slice(magic);
+    out.extend_from_slice(&(content.len() as u64).to_ne_bytes());
+    out.extend_from_slice(content);
+}
+
+//the addresses in the content of a TRACEBUF chunk without the high bits, skipping the zeroed entries
+//of a buffer that wasn't filled
+pub fn tracebuf_addresses(content: &[u8]) -> impl Iterator<Item = u64> + '_ {
+    content.chunks_exact(ENTRY_LEN).filter_map(|entry| {
+        let address = u64::from_ne_bytes(entry[..8].try_into().unwrap());
+        let cycle = u64::from_ne_bytes(entry[8..].try_into().unwrap());
+        (address != 0 || cycle != 0).then_some(address & ADDRESS_MASK)
+    })
+}
+
+//a PROCMAPS chunk with the BUILDIDS chunk following it, and the addresses in the TRACEBUF chunks
+//up to the next PROCMAPS chunk (which are interpreted according to these memory maps)
+#[derive(Default)]
+pub struct MemoryMapGroup<'a> {
+    pub proc_maps: &'a [u8],
+    pub build_ids: Option<&'a [u8]>,
+    pub last_chunk: usize, //the index of the PROCMAPS chunk or of the BUILDIDS chunk following it
+    pub addresses: BTreeSet<u64>,
+}
+
+pub fn memory_map_groups(chunks: &[Chunk]) -> Vec<MemoryMapGroup<'_>> {
+    let mut groups: Vec<MemoryMapGroup> = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        match (&chunk.magic, groups.last_mut()) {
+            (b"PROCMAPS", _) => groups.push(MemoryMapGroup { proc_maps: &chunk.content, last_chunk: i, ..Default::default() }),
+            (b"BUILDIDS", Some(group)) if group.last_chunk + 1 == i && group.build_ids.is_none() => {
+                group.build_ids = Some(&chunk.content);
+                group.last_chunk = i;
+            }
+            (b"TRACEBUF", Some(group)) => group.addresses.extend(tracebuf_addresses(&chunk.content)),
+            _ => {}
+        }
+    }
+    groups
+}
+
+pub struct FunCount<'a> {
+    pub proc_maps: &'a [u8],
+    pub build_ids: Option<&'a [u8]>,
+    pub counts: Vec<(u64, u64)>, //(address, count)
+    pub symtable: Option<&'a [u8]>,
+    pub without_symtable: &'a str, //the text up to the SYMTABLE line
+}
+
+pub fn parse_funcount(text: &str) -> io::Result<FunCount<'_>> {
+    const HEADER: &str = "FUNCOUNT\nPROCMAPS\n";
+    if !text.starts_with(HEADER) {
+        return Err(invalid(format!("missing FUNCOUNT & PROCMAPS magic strings - got `{}'", text.lines().next().unwrap_or(""))));
+    }
+    //where the line with the given text starts, searching from the given position (which is at the start of a line)
+    let find_line = |line: &str, from: usize| text[from-1..].find(&format!("\n{}\n", line)).map(|pos| from + pos);
+    let counts_start = find_line("COUNTS", HEADER.len()).ok_or(invalid("COUNTS magic string not found".to_string()))?;
+    let build_ids_start = find_line("BUILDIDS", HEADER.len()).filter(|&start| start < counts_start);
+    let symtable_start = find_line("SYMTABLE", counts_start);
+
+    let mut counts = Vec::new();
+    for line in text[counts_start + "COUNTS\n".len()..symtable_start.unwrap_or(text.len())].lines() {
+        let bad_line = || invalid(format!("invalid address-count pair `{}'", line));
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let [address, count] = parts[..] else { return Err(bad_line()) };
+        let address = u64::from_str_radix(address.trim_start_matches("0x"), 16).map_err(|_| bad_line())?;
+        let count = count.parse::<u64>().map_err(|_| bad_line())?;
+        counts.push((address, count));
+    }
+    Ok(FunCount {
+        proc_maps: &text.as_bytes()[HEADER.len()..build_ids_start.unwrap_or(counts_start)],
+        build_ids: build_ids_start.map(|start| &text.as_bytes()[start + "BUILDIDS\n".len()..counts_start]),
+        counts,
+        symtable: symtable_start.map(|start| text[start + "SYMTABLE\n".len()..].trim_end().as_bytes()),
+        without_symtable: &text[..symtable_start.unwrap_or(text.len())],
+    })
+}
@@ -2,6 +2,11 @@
 import json
 import os
 import glob
+import contextlib
+import shutil
+import socket
+import subprocess
+import time
 from multiprocessing import Pool
 
 call='+'
@@ -26,6 +31,8 @@ def parse_perfetto_json(fname):
                 if thread_names[tid] in threads: # not a unique name
                     thread_names[tid] += '.%d'%tid # mangle by tid
             continue
+        if phase == 'i': # instant events, such as exception catches
+            continue
         assert phase == 'X' # complete event
         timepoints = threads.setdefault(thread_names[tid], list())
         timestamp = event['ts']
@@ -165,7 +172,6 @@ unfortunate_full_unwinding = [
     (ret,'__cxa_end_catch'),
 ]
 
-def caller_wrapping(events): return [(call,'caller')] + events + [(ret,'caller')]
 def catcher_wrapping(events): return [(call,'catcher')] + events + [(ret,'catcher')]
 
 # in the "dirty" untraced caller case, we have 2 artifacts:
@@ -176,7 +182,8 @@ def catcher_wrapping(events): return [(call,'catcher')] + events + [(ret,'catche
 #    (this artifact could be avoided in most cases by doing more work in the tracer but it wouldn't
 #    solve the 1st artifact, definitely not when the return from the caller was never logged, eg because it didn't happen
 #    by the time the snapshot was taken)
-dirty_untraced_caller_ref = caller_wrapping(caller_wrapping(caller_wrapping(unfortunate_full_unwinding) + unfortunate_full_unwinding) + unfortunate_full_unwinding)
+# funtrace2viz now recovers from both artifacts when the caller of the catcher returns, by finding its call in the
+# history of stacks emptied by catches; XRay, where the returns are logged differently, still produces the "dirty" trace below
 # with XRay, the catcher is the one for which we see "orphan returns" after unwinding [due to XRay's funky return address logging]
 dirty_untraced_catcher_xray_ref = catcher_wrapping(catcher_wrapping(catcher_wrapping(unfortunate_full_unwinding) + unfortunate_full_unwinding) + unfortunate_full_unwinding)
 
@@ -373,6 +380,58 @@ BUILDDIR = './built-tests'
 OUTDIR = './out'
 TARGET = 'x86_64-unknown-linux-gnu'
 
+@contextlib.contextmanager
+def binaries_hidden():
+    os.rename(BUILDDIR, BUILDDIR+'.hidden')
+    try:
+        yield
+    finally:
+        os.rename(BUILDDIR+'.hidden', BUILDDIR)
+
+@contextlib.contextmanager
+def file_replaced(path, replacement):
+    '''moves replacement to path, restoring the original file afterwards'''
+    os.rename(path, path+'.orig')
+    os.rename(replacement, path)
+    try:
+        yield
+    finally:
+        os.rename(path+'.orig', path)
+
+def lib_rebuilt(cpp, mode, flags='', compiler_mode=None):
+    '''rebuilds a test's shared library with -O1 instead of -O3, so it has another build ID, as happens
+    when a program is rebuilt after its trace was taken; optionally with the compiler of another mode'''
+    lib = f'{BUILDDIR}/{cpp.split(".")[0]}.{mode}.so'
+    system(f'./compiler-wrappers/funtrace-{COMPILERS[compiler_mode or mode]} tests/{cpp} -o {lib}.rebuilt -O1 -std=c++11 {flags} -I. -fPIC -shared')
+    return file_replaced(lib, lib+'.rebuilt')
+
+def source_line(source, marker):
+    '''the number of the line with the marker'''
+    return [i+1 for i,line in enumerate(open(source)) if marker in line][0]
+
+def build_id(binary):
+    return [line.split()[-1] for line in os.popen(f'readelf -n {binary}') if 'Build ID:' in line][0]
+
+@contextlib.contextmanager
+def http_server(directory):
+    '''serves the directory at the URL it yields'''
+    with socket.socket() as s:
+        s.bind(('127.0.0.1', 0))
+        port = s.getsockname()[1]
+    server = subprocess.Popen(['python3', '-m', 'http.server', '--bind', '127.0.0.1', '--directory', directory, str(port)],
+                              stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL)
+    try:
+        for _ in range(100): # wait for the server to start listening
+            try:
+                socket.create_connection(('127.0.0.1', port)).close()
+                break
+            except OSError:
+                time.sleep(0.1)
+        yield f'http://127.0.0.1:{port}'
+    finally:
+        server.kill()
+        server.wait()
+
 def build_trace_analysis_tools():
     system(f'RUSTFLAGS="-C target-feature=+crt-static" cargo build -r --target {TARGET}')
 
@@ -380,21 +439,25 @@ def run_cmds(cmds):
     for cmd in cmds:
         system(cmd)
 
-def build_cxx_test(main, shared=[], dyn_shared=[], flags=''):
+def compilers_support(flags):
+    return all(os.system(f'echo "int main() {{}}" | {cxx} -x c++ {flags} -o /dev/null - 2> /dev/null') == 0 for cxx in ['g++','clang++'])
+
+COMPILERS = {
+   'fi-gcc':'finstr-g++',
+   'fi-clang':'finstr-clang++',
+   'pg':'pg-g++',
+   'xray':'xray-clang++',
+}
+
+def build_cxx_test(main, shared=[], dyn_shared=[], flags='', name=None):
     cmdlists = []
     binaries = {}
     for mode in ['fi-gcc','fi-clang','pg','xray']:
         CXXFLAGS=f"-O3 -std=c++11 -Wall {flags}"
         if mode == 'xray':
             CXXFLAGS += " -fxray-instruction-threshold=1"
-        compiler = {
-           'fi-gcc':'finstr-g++',
-           'fi-clang':'finstr-clang++',
-           'pg':'pg-g++',
-           'xray':'xray-clang++',
-        }
-        CXX = f'./compiler-wrappers/funtrace-{compiler[mode]}'
-        test = main.split('.')[0]
+        CXX = f'./compiler-wrappers/funtrace-{COMPILERS[mode]}'
+        test = name or main.split('.')[0]
         binary = f'{BUILDDIR}/{test}.{mode}'
         cmds = []
         LIBS = ''
@@ -402,6 +465,8 @@ def build_cxx_test(main, shared=[], dyn_shared=[], flags=''):
         if shared or dyn_shared:
             for cpp in shared+dyn_shared:
                 module = cpp.split('.')[0]
+                if name: # don't clash with the libraries of the test built under its own name
+                    module = f'{name}_{module}'
                 lib = f'{os.path.realpath(BUILDDIR)}/{module}.{mode}.so'
                 cmds += [
                     f'{CXX} -c tests/{cpp} -o {BUILDDIR}/{module}.{mode}.o {CXXFLAGS} -I. -fPIC',
@@ -476,7 +541,17 @@ def main():
     buildcmds('asm_filter.cpp',flags=f'-funtrace-instr-thresh=20 -funtrace-no-trace={os.path.realpath("tests/no-trace.txt")} -funtrace-do-trace={os.path.realpath("tests/do-trace.txt")}')
     buildcmds('asm_filter_2.cpp',flags=f'-funtrace-instr-thresh=20 -funtrace-ignore-loops')
     buildcmds('shared.cpp',shared=['lib_shared.cpp'],dyn_shared=['lib_dyn_shared.cpp'])
+    # split DWARF - the function DIEs are only in the .dwo files (or in .dwp packages made from them)
+    buildcmds('shared.cpp',shared=['lib_shared.cpp'],dyn_shared=['lib_dyn_shared.cpp'],flags='-gsplit-dwarf',name='shared_split')
+    buildcmds('inline.cpp',flags='-O2')
+    buildcmds('untraced_funcs.cpp',flags='-Wl,--build-id=none',name='symbol_index') # indexed by path, mtime & size without a build ID
     buildcmds('count.cpp',shared=['count_shared.cpp'],dyn_shared=['count_dyn_shared.cpp'],flags='-DFUNTRACE_FUNCOUNT -DFUNCOUNT_PAGE_TABLES=2')
+    # compressed debug sections (.zdebug_* / SHF_COMPRESSED) should resolve to the same source lines
+    for compression in ['zlib','zstd']:
+        if not compilers_support(f'-g -gz={compression}'):
+            print(f'NOTE: skipping the -gz={compression} test - not supported by the compiler or the linker')
+            continue
+        buildcmds('count.cpp',shared=['count_shared.cpp'],dyn_shared=['count_dyn_shared.cpp'],flags=f'-DFUNTRACE_FUNCOUNT -DFUNCOUNT_PAGE_TABLES=2 -gz={compression}',name=f'count_{compression}')
     buildcmds('c.c')
     pool.map(run_cmds, cmdlists)
 
@@ -508,6 +583,7 @@ def check():
         return jsonmod.load(open(json))['systemTraceEvents']
 
     def jsons(test): return sorted(glob.glob(f'{OUTDIR}/{test}.*/funtrace.json'))
+    def functions(json): return jsonmod.load(open(json))['file_info']['functions']
 
     # funtrace tests [except freq]
     for json in jsons('ignore_disable'):
@@ -523,11 +599,42 @@ def check():
                 assert False, f'unexpected thread name: {name}'
     for json in jsons('exceptions'):
         print('checking',json)
-        assert verify_thread(load_thread(json), exceptions_ref)
+        thread = load_thread(json)
+        assert verify_thread(thread, exceptions_ref)
+        # every catch is shown as an instant event on the catching thread
+        catches = [e for e in jsonmod.load(open(json))['traceEvents'] if e['ph'] == 'i' and e['name'] == 'catch']
+        assert len(catches) == len([name for what,name,_ in thread if what == call and name.startswith('catcher')])
+        assert all(e['args']['catcher'].startswith('catcher') for e in catches)
+        # gcc -finstrument-functions logs the returns of the unwound functions, listed by the catch as returned before it
+        # (but not __cxa_throw, whose call is logged without CALL_RETURNING_UPON_THROW_BIT); other methods log no such returns
+        unwound_ref = ['thrower','wrapper_call','wrapper_tailcall_1','wrapper_tailcall_2','wrapper_call_outer'] if 'fi-gcc' in json else []
+        for e in catches:
+            returned = [u['function'].split('(')[0] for u in e['args']['unwound'] if u['closed_by'] == 'returned_before_catch']
+            assert returned == unwound_ref, f'wrong frames returned before the catch: {returned}'
+        # --dry and --grep decode the catches without writing the events anywhere
+        testdir = os.path.dirname(json)
+        system(f'./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw {testdir}/funtrace --dry > /dev/null')
+        system(f'./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw -g catcher > /dev/null')
     for json in jsons('untraced_catcher'):
         print('checking',json)
-        ref = clean_untraced_caller_ref if 'fi-gcc' in json else (dirty_untraced_caller_ref if 'xray' not in json else dirty_untraced_catcher_xray_ref)
+        ref = clean_untraced_caller_ref if 'xray' not in json else dirty_untraced_catcher_xray_ref
         assert verify_thread(load_thread(json), ref)
+        # decoding anomalies shouldn't fail --dry and --grep, which don't write the anomaly events anywhere
+        testdir = os.path.dirname(json)
+        system(f'./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw {testdir}/funtrace --dry > /dev/null')
+        system(f"./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw -g 'catcher' > /dev/null")
+        if '.pg/' in json:
+            # -pg doesn't log returns upon throw, so each catch in the untraced catcher empties the stack, and the return
+            # of caller() recovers it from there - it's not among the frames unwound by the catch
+            events = jsonmod.load(open(json))['traceEvents']
+            recovered = [e['name'] for e in events if e['ph'] == 'X' and e.get('args',{}).get('recovered_after_uninstrumented_catch')]
+            assert len(recovered) == 3 and all(name.startswith('caller') for name in recovered), f'wrong recovered calls: {recovered}'
+            for catch in [e for e in events if e['ph'] == 'i' and e['name'] == 'catch']:
+                closed_by_catch = [u['function'] for u in catch['args']['unwound'] if u['closed_by'] == 'catch']
+                assert len(closed_by_catch) == 5 and not any(f.startswith('caller') for f in closed_by_catch), f'wrong unwound frames: {closed_by_catch}'
+            system(f'./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw {testdir}/quality --quality-report > /dev/null')
+            quality = jsonmod.load(open(f'{testdir}/quality.quality.json'))['threads'][0]
+            assert quality['recovered_calls'] == 3 and quality['catches'] == 3 and quality['frames_unwound_by_catches'] == 3*5, f'wrong decode quality: {quality}'
     for json in jsons('untraced_funcs'): 
         print('checking',json)
         assert verify_thread(load_thread(json), untraced_funcs_ref)
@@ -537,9 +644,21 @@ def check():
     for json in jsons('tailcall'):
         print('checking',json)
         assert verify_thread(load_thread(json), tailcall_clean_ref if 'fi-' in json else tailcall_dirty_ref)
+        # the --tail-calls pass finds the tail calls by disassembling the binary and undoes the artifacts,
+        # and leaves the traces of -finstrument-functions, which has no such artifacts, as they were
+        fixed = os.path.join(os.path.dirname(json), 'funtrace.tailcalls')
+        system(f'./target/{TARGET}/release/funtrace2viz {os.path.dirname(json)}/funtrace.raw {fixed} --tail-calls > /dev/null')
+        if '.pg/' in json or 'fi-' in json:
+            assert verify_thread(load_thread(fixed+'.json'), tailcall_clean_ref)
     for json in jsons('orphans'): 
         print('checking',json)
         assert verify_thread(load_thread(json), orphans_ref(json))
+        anomalies = [e['name'] for e in jsonmod.load(open(json))['traceEvents'] if e['ph'] == 'i' and e['cat'] == 'anomaly']
+        assert anomalies.count('orphan return') == 3 and anomalies.count('no return logged') == 1
+        # decoding anomalies shouldn't fail --dry and --grep, which don't write the anomaly events anywhere
+        testdir = os.path.dirname(json)
+        system(f'./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw {testdir}/funtrace --dry > /dev/null')
+        system(f"./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw -g '\\?\\?|orphan' > /dev/null")
     for json in jsons('buf_size'): 
         print('checking',json)
         threads = load_threads(json)
@@ -554,6 +673,158 @@ def check():
         print('checking',json)
         for thread in load_threads(json).values():
             assert verify_thread(thread, shared_ref)
+        # funsymbolize saves the symbols in the trace so it decodes the same with the binaries gone
+        symbolized = os.path.join(os.path.dirname(json), 'funtrace.symbolized')
+        system(f'./target/{TARGET}/release/funsymbolize {os.path.dirname(json)}/funtrace.raw {symbolized}.raw --sources > /dev/null')
+        with binaries_hidden():
+            system(f'./target/{TARGET}/release/funtrace2viz {symbolized}.raw {symbolized} > /dev/null')
+        for thread in load_threads(symbolized+'.json').values():
+            assert verify_thread(thread, shared_ref)
+        # funpack bundles the binaries with the trace, and the substitute-path.json written by unpacking it points to them
+        testdir = os.path.dirname(json)
+        tools = os.path.realpath(f'./target/{TARGET}/release')
+        system(f'{tools}/funpack pack {testdir}/funtrace.raw {testdir}/funtrace.tar.gz > /dev/null')
+        system(f'{tools}/funpack unpack {testdir}/funtrace.tar.gz {testdir}/unpacked > /dev/null')
+        with binaries_hidden():
+            system(f'cd {testdir}/unpacked && {tools}/funtrace2viz funtrace.raw funtrace > /dev/null')
+        for thread in load_threads(f'{testdir}/unpacked/funtrace.json').values():
+            assert verify_thread(thread, shared_ref)
+        # the instrumentation method of every binary is detected from the calls to the entry hooks
+        mode = testdir.split('.')[-1]
+        METHODS = {'fi-gcc':'-finstrument-functions','fi-clang':'-finstrument-functions','pg':'-pg','xray':'XRay'}
+        def instrumentation(testdir):
+            system(f'./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw {testdir}/quality --quality-report > {testdir}/quality.out')
+            binaries = jsonmod.load(open(f'{testdir}/quality.quality.json'))['instrumentation']
+            return {os.path.basename(binary['executable_file']):binary['methods'] for binary in binaries}, open(f'{testdir}/quality.out').read()
+        methods, _ = instrumentation(testdir)
+        assert methods == {name:[METHODS[mode]] for name in [f'shared.{mode}',f'lib_shared.{mode}.so',f'lib_dyn_shared.{mode}.so']}, f'wrong instrumentation: {methods}'
+        if mode == 'pg':
+            # a process mixing methods - the -pg executable loading a library built with -finstrument-functions
+            mixed = f'{testdir}/mixed'
+            with lib_rebuilt('lib_shared.cpp', mode, compiler_mode='fi-gcc'):
+                system(f'mkdir -p {mixed} && cd {mixed} && {os.path.realpath(BUILDDIR)}/shared.{mode}')
+                methods, out = instrumentation(mixed)
+            assert methods == {f'shared.{mode}':['-pg'], f'lib_shared.{mode}.so':['-finstrument-functions'], f'lib_dyn_shared.{mode}.so':['-pg']}, f'wrong instrumentation: {methods}'
+            assert 'WARNING: the traced binaries use different instrumentation methods (-finstrument-functions, -pg)' in out, out
+        # the debug info of a stripped library is downloaded from a debuginfod server into the cache, by build ID, and
+        # symbolizes the trace like the unstripped library (--no-symbol-index since the index would have the symbols)
+        lib = f'{BUILDDIR}/lib_shared.{mode}.so'
+        lib_build_id = build_id(lib)
+        served = f'{testdir}/debuginfod/buildid/{lib_build_id}/debuginfo'
+        os.makedirs(os.path.dirname(served), exist_ok=True)
+        system(f'objcopy --only-keep-debug {lib} {served}')
+        system(f'strip {lib} -o {lib}.stripped')
+        cache = f'{testdir}/debuginfod-cache'
+        funtrace2viz = f'env DEBUGINFOD_URLS= DEBUGINFOD_CACHE_PATH={cache} ./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw'
+        with file_replaced(lib, f'{lib}.stripped'), http_server(f'{testdir}/debuginfod') as url:
+            system(f'{funtrace2viz} {testdir}/stripped --no-symbol-index > /dev/null')
+            system(f'{funtrace2viz} {testdir}/debuginfod --no-symbol-index --debuginfod {url} > /dev/null')
+        assert functions(f'{testdir}/stripped.json') != functions(json)
+        assert functions(f'{testdir}/debuginfod.json') == functions(json)
+        assert os.listdir(cache) == [lib_build_id] and os.listdir(f'{cache}/{lib_build_id}') == ['debuginfo']
+        assert open(f'{cache}/{lib_build_id}/debuginfo','rb').read() == open(served,'rb').read()
+        # ...or found locally - by the .gnu_debuglink of the stripped library, if its CRC matches, or by the build ID
+        # under a debug directory (as .build-id/xx/yyyy.debug)
+        debug = f'{lib}.debug'
+        system(f'objcopy --only-keep-debug {lib} {debug}')
+        system(f'strip {lib} -o {lib}.debuglink && objcopy --add-gnu-debuglink={debug} {lib}.debuglink')
+        build_id_debug = f'{testdir}/debug/.build-id/{lib_build_id[:2]}/{lib_build_id[2:]}.debug'
+        os.makedirs(os.path.dirname(build_id_debug), exist_ok=True)
+        shutil.copy(debug, build_id_debug)
+        funtrace2viz = f'env FUNTRACE_DEBUG_DIRS= DEBUGINFOD_URLS= ./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw'
+        with file_replaced(lib, f'{lib}.debuglink'):
+            system(f'{funtrace2viz} {testdir}/debuglink --no-symbol-index > /dev/null')
+            with open(debug, 'ab') as f:
+                f.write(b'\0')
+            system(f'{funtrace2viz} {testdir}/bad_crc --no-symbol-index > {testdir}/bad_crc.out')
+        system(f'strip {lib} -o {lib}.stripped')
+        with file_replaced(lib, f'{lib}.stripped'):
+            system(f'{funtrace2viz} {testdir}/build_id --no-symbol-index --debug-dir {testdir}/debug > /dev/null')
+        assert functions(f'{testdir}/debuglink.json') == functions(json)
+        assert functions(f'{testdir}/bad_crc.json') != functions(json)
+        assert f"WARNING: ignoring {os.path.realpath(debug)} - its CRC doesn't match" in open(f'{testdir}/bad_crc.out').read()
+        assert functions(f'{testdir}/build_id.json') == functions(json)
+        # a library rebuilt since the trace was taken is refused because of its build ID, rather than used
+        # to symbolize the trace wrongly
+        with lib_rebuilt('lib_shared.cpp', mode):
+            system(f'./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw {testdir}/rebuilt --no-symbol-index > {testdir}/rebuilt.out')
+        out = open(f'{testdir}/rebuilt.out').read()
+        assert 'ERROR: no file with build ID' in out and f'lib_shared.{mode}.so' in out, f'no build ID mismatch reported: {out}'
+        names = {name for thread in load_threads(f'{testdir}/rebuilt.json').values() for _,name,_ in thread}
+        assert any(name.startswith('??') for name in names) and not any(name.startswith('f_shared') for name in names), f'wrong names: {names}'
+        assert any(name.startswith('f_dyn_shared') for name in names), f'wrong names: {names}'
+    for json in jsons('shared_split'):
+        print('checking',json)
+        for thread in load_threads(json).values():
+            assert verify_thread(thread, shared_ref)
+        # the declaration lines of the functions come from their DIEs in the .dwo files, so they should be the same
+        # as with all of the debug info in the binaries (--no-symbol-index since the index would remember the lines)
+        testdir = os.path.dirname(json)
+        mode = testdir.split('.')[-1]
+        unsplit = functions(f'{OUTDIR}/shared.{mode}/funtrace.json')
+        system(f'./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw {testdir}/dwo --no-symbol-index > /dev/null')
+        assert functions(f'{testdir}/dwo.json') == unsplit
+        # the libraries' .dwo files packed into .dwp files under the debug directory of a --sysroot are found there
+        if not shutil.which('llvm-dwp'): # binutils' dwp drops the DWARF 5 units gcc emits by default
+            print('NOTE: skipping the .dwp test - llvm-dwp not found')
+            continue
+        dwos = [f'{BUILDDIR}/shared_split_{lib}.{mode}.dwo' for lib in ['lib_shared','lib_dyn_shared']]
+        for dwo in dwos:
+            dwp = f'{testdir}/sysroot/usr/lib/debug{os.path.realpath(dwo[:-len(".dwo")])}.so.dwp'
+            os.makedirs(os.path.dirname(dwp), exist_ok=True)
+            system(f'llvm-dwp {dwo} -o {dwp}')
+            os.rename(dwo, f'{dwo}.hidden')
+        try:
+            # without the .dwo files, the lines of the libraries' functions are guessed
+            system(f'./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw {testdir}/nodwo --no-symbol-index > /dev/null')
+            assert functions(f'{testdir}/nodwo.json') != unsplit
+            system(f'./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw {testdir}/dwp --sysroot {testdir}/sysroot --no-symbol-index > /dev/null')
+            assert functions(f'{testdir}/dwp.json') == unsplit
+        finally:
+            for dwo in dwos:
+                os.rename(f'{dwo}.hidden', dwo)
+    for json in jsons('symbol_index'):
+        print('checking',json)
+        assert verify_thread(load_thread(json), untraced_funcs_ref)
+        # a rerun decodes the same using the symbol index saved by the first run; the index of a binary without
+        # a build ID is named by its modification time & size, so changing either makes the next run parse the binary
+        testdir = os.path.dirname(json)
+        binary = os.path.realpath(f'{BUILDDIR}/{os.path.basename(testdir)}')
+        def decode(name):
+            system(f'env FUNTRACE_SYMBOL_INDEX_DIR={testdir}/index ./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw {testdir}/{name} > {testdir}/{name}.out')
+            index_hit = any(line.startswith('  using the symbol index ') and line.endswith(f' for {binary}') for line in open(f'{testdir}/{name}.out').read().split('\n'))
+            return jsonmod.load(open(f'{testdir}/{name}.json')), index_hit
+        first, index_hit = decode('first')
+        assert not index_hit
+        assert decode('rerun') == (first, True)
+        stat = os.stat(binary)
+        os.utime(binary, (stat.st_atime, stat.st_mtime+10))
+        assert decode('touched') == (first, False)
+        assert decode('touched_rerun') == (first, True)
+        with open(binary, 'ab') as f:
+            f.write(b'\0')
+        assert decode('resized') == (first, False)
+    for json in jsons('inline'):
+        print('checking',json)
+        # --inline-frames adds the chain of functions inlined at the catch to the catch events, the outermost first
+        testdir = os.path.dirname(json)
+        system(f'./target/{TARGET}/release/funtrace2viz {testdir}/funtrace.raw {testdir}/inline --inline-frames --no-symbol-index > /dev/null')
+        def catches(json): return [e for e in jsonmod.load(open(json))['traceEvents'] if e['ph'] == 'i' and e['name'] == 'catch']
+        assert len(catches(json)) == 3 and not any('catch_site' in catch['args'] for catch in catches(json))
+        for catch in catches(f'{testdir}/inline.json'):
+            frames = catch['args']['catch_site'].split(' > ')
+            assert [frame.split(' (')[0] for frame in frames] == ['multi_line_signature(int, int, int, int)', 'inlined_catcher'], f'wrong catch site: {frames}'
+            # the lines are those of the exact address - the call site of the inlined function rather than the start of its
+            # caller, and the catch (except where gcc moved the catch block into a [clone .cold] part whose first instruction,
+            # the call to __cxa_begin_catch, has no line info)
+            assert frames[0].endswith(f'inline.cpp:{source_line("tests/inline.cpp", "inlined call site")})'), f'wrong call site: {frames[0]}'
+            if '.pg/' in json:
+                assert frames[1].endswith(f'inline.cpp:{source_line("tests/inline.cpp", "catch site")})'), f'wrong catch site: {frames[1]}'
+        # the functions point at their declaration lines from the debug info - the line of the name in a multi-line signature,
+        # including for a function split into a hot and a [clone .cold] part (under -finstrument-functions)
+        decl_lines = {name.split(' (')[0]:line for name,(_,line) in functions(f'{testdir}/inline.json').items() if 'inline.cpp' in name}
+        assert decl_lines == {'thrower()':source_line('tests/inline.cpp', 'void NI thrower'),
+                              'multi_line_signature(int, int, int, int)':source_line('tests/inline.cpp', '// declaration')}, f'wrong declaration lines: {decl_lines}'
     for json in jsons('asm_filter'):
         print('checking',json)
         if 'xray' not in json: # we don't support asm filtering for XRay
@@ -568,9 +839,24 @@ def check():
         assert verify_thread(load_thread(json), c_ref)
 
     # funcount test
-    for symcount_txt in sorted(glob.glob(f'{OUTDIR}/count.*/symcount.txt')):
+    for symcount_txt in sorted(glob.glob(f'{OUTDIR}/count*.*/symcount.txt')):
         print('checking',symcount_txt)
         check_count_results(symcount_txt)
+        testdir = os.path.dirname(symcount_txt)
+        system(f'./target/{TARGET}/release/funsymbolize {testdir}/funcount.txt {testdir}/funcount.symbolized.txt > /dev/null')
+        with binaries_hidden():
+            system(f'./target/{TARGET}/release/funcount2sym {testdir}/funcount.symbolized.txt | c++filt > {testdir}/symcount.symbolized.txt')
+        check_count_results(f'{testdir}/symcount.symbolized.txt')
+        # the counts of a library rebuilt since they were taken are reported as ?? rather than attributed to
+        # the wrong functions
+        if not os.path.basename(testdir).startswith('count.'): # the count_{compression} variants needn't repeat this
+            continue
+        mode = testdir.split('.')[-1]
+        with lib_rebuilt('count_shared.cpp', mode, '-DFUNTRACE_FUNCOUNT -DFUNCOUNT_PAGE_TABLES=2'):
+            system(f'./target/{TARGET}/release/funcount2sym {testdir}/funcount.txt > {testdir}/symcount.rebuilt.txt')
+        rebuilt = open(f'{testdir}/symcount.rebuilt.txt').read()
+        assert 'ERROR: no file with build ID' in rebuilt and f'count_shared.{mode}.so' in rebuilt, f'no build ID mismatch reported: {rebuilt}'
+        assert '??' in rebuilt and 'f_shared' not in rebuilt and 'f_dyn_shared' in rebuilt, f'wrong symbols: {rebuilt}'
 
     # check last... might fail intermittently because we sleep for more than we asked for
     # due to the machine being loaded or whatever
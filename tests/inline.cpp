@@ -0,0 +1,41 @@
+#include "test.h"
+
+volatile int n;
+
+void NI thrower()
+{
+    n++;
+    throw "error";
+}
+
+//inlined into its caller (and not traced itself, so -finstrument-functions doesn't log it
+//as a call even though it instruments before inlining)
+static inline __attribute__((always_inline)) void NOFUNTRACE inlined_catcher()
+{
+    try {
+        thrower();
+    }
+    catch(...) { // catch site
+        n++;
+    }
+}
+
+//the declaration line is that of the name, which line-3 wouldn't guess right
+void NI
+multi_line_signature(int a, // declaration
+                     int b,
+                     int c,
+                     int d)
+{
+    n += a+b+c+d;
+    inlined_catcher(); // inlined call site
+    n++;
+}
+
+int main()
+{
+    scope_tracer tracer;
+    for(int i=0; i<3; ++i) {
+        multi_line_signature(1, 2, 3, 4);
+    }
+}